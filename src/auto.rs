@@ -0,0 +1,105 @@
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::time::Duration;
+
+use tokio::sync;
+
+use crate::download::{Download, DownloadStatus};
+
+const MAX_CONNECTION_COUNT: u8 = 16;
+// a connection is worth keeping if it makes the download this much faster
+const MIN_GAIN: f64 = 0.1;
+// new connections need a moment to get up to speed before measuring
+const WARMUP: Duration = Duration::from_secs(2);
+const SAMPLE_COUNT: u32 = 3;
+// chunks are sized to take each connection about this long
+const CHUNK_TIME: u64 = 10;
+// the chunk sizes --auto picks from, splitting goes below with download::MIN_CHUNK_SIZE
+const MIN_AUTO_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_AUTO_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+enum Interruption {
+    /// The download is retrying after an error.
+    Failed,
+    /// Paused, queued or ended.
+    Stopped,
+}
+
+/// --auto: starts with one connection and keeps adding one while that makes the download
+/// faster, sizing the chunks to the speed of each connection. An error, e.g. a 429 or 503
+/// response, halves the connections and keeps them at most there. Runs until the caller
+/// stops polling it.
+pub async fn tune(download: &Download) {
+    let mut status = download.status_receiver.clone();
+    let mut max_connection_count = MAX_CONNECTION_COUNT;
+    // the fastest connection count measured since the download last (re)started
+    let mut best: Option<(u8, u64)> = None;
+    loop {
+        while *status.borrow_and_update() != DownloadStatus::Downloading {
+            if status.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+        let connection_count = download.connection_count().get();
+        let speed = match measure(download, &mut status).await {
+            Ok(speed) => speed,
+            Err(Interruption::Failed) => {
+                max_connection_count = (connection_count / 2).max(1);
+                download.set_connection_count(NonZeroU8::new(max_connection_count).unwrap());
+                best = None;
+                continue;
+            }
+            Err(Interruption::Stopped) => {
+                best = None;
+                continue;
+            }
+        };
+
+        match best {
+            Some((best_count, best_speed)) if connection_count > best_count
+                && (speed as f64) < best_speed as f64 * (1.0 + MIN_GAIN) => {
+                // the last connection did not help, go back and stay there
+                max_connection_count = best_count;
+                download.set_connection_count(NonZeroU8::new(best_count).unwrap());
+            }
+            _ => best = Some((connection_count, speed)),
+        }
+        if let Some((best_count, best_speed)) = best {
+            let chunk_size = (best_speed / u64::from(best_count) * CHUNK_TIME) as usize;
+            download.set_chunk_size(NonZeroUsize::new(chunk_size.clamp(MIN_AUTO_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)).unwrap());
+            if best_count == connection_count && connection_count < max_connection_count {
+                download.set_connection_count(NonZeroU8::new(connection_count + 1).unwrap());
+                continue;
+            }
+        }
+        // settled, only an error or a restart changes anything now
+        if status.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        if matches!(*status.borrow(), DownloadStatus::Retrying { .. }) {
+            max_connection_count = (download.connection_count().get() / 2).max(1);
+            download.set_connection_count(NonZeroU8::new(max_connection_count).unwrap());
+        }
+        best = None;
+    }
+}
+
+/// Average speed reported by the speed tracker after a warmup.
+async fn measure(download: &Download, status: &mut sync::watch::Receiver<DownloadStatus>) -> Result<u64, Interruption> {
+    let mut speed = 0;
+    for sample in 0..=SAMPLE_COUNT {
+        let wait = if sample == 0 { WARMUP } else { Duration::from_secs(1) };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            r = status.changed() => {
+                return Err(match (r, &*status.borrow()) {
+                    (Ok(_), DownloadStatus::Retrying { .. }) => Interruption::Failed,
+                    _ => Interruption::Stopped,
+                });
+            }
+        }
+        if sample > 0 {
+            speed += download.speed();
+        }
+    }
+    Ok(speed / u64::from(SAMPLE_COUNT))
+}
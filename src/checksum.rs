@@ -0,0 +1,128 @@
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumMismatchAction {
+    /// Keep the file
+    Keep,
+    /// Delete the file
+    Delete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Length of the hex encoded digest.
+    fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+            HashAlgorithm::Sha1 => 40,
+            HashAlgorithm::Md5 => 32,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            "sha1" | "sha-1" => Ok(HashAlgorithm::Sha1),
+            "md5" => Ok(HashAlgorithm::Md5),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("unsupported hash algorithm `{s}`, expected sha256, sha1, md5 or blake3")),
+        }
+    }
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Blake3 => "blake3",
+        })
+    }
+}
+
+/// An expected digest, written as `<algorithm>=<hex>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub expected: String,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, expected) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid checksum `{s}`, expected `<algorithm>=<hex>`"))?;
+        let algorithm = algorithm.trim().parse::<HashAlgorithm>()?;
+        let expected = expected.trim().to_ascii_lowercase();
+        if expected.len() != algorithm.hex_len() || !expected.bytes().all(|n| n.is_ascii_hexdigit()) {
+            return Err(format!("invalid {algorithm} checksum `{expected}`, expected {} hex digits", algorithm.hex_len()));
+        }
+        Ok(Self { algorithm, expected })
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Returns the lowercase hex digest.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Sha1(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Md5(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Blake3(hasher) => to_hex(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, n| {
+        let _ = write!(hex, "{n:02x}");
+        hex
+    })
+}
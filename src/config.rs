@@ -0,0 +1,109 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+
+/// `$XDG_CONFIG_HOME/hd/config`, or `~/.config/hd/config`.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("hd").join("config"))
+}
+
+/// The value of `--config` in the command line, which has to be known before parsing it.
+pub fn path_from_args(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Reads options in the form of the long command line options, one `name = value` per
+/// line (or just `name` for flags) with `#` comments:
+///
+/// ```text
+/// connection-count = 8
+/// limit-schedule = 08:00-18:00=2M,18:00-08:00=0
+/// ```
+///
+/// Each option is checked against `command` on its own, so an error names its line.
+pub fn read(path: &Path, command: &clap::Command) -> Result<Vec<OsString>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| anyhow::Error::msg(format!("{}: {err}", path.display())))?;
+    let mut args = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        let name = name.trim_start_matches("--");
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            anyhow::bail!("{}:{}: expected `name = value`", path.display(), index + 1);
+        }
+        let arg = match value {
+            Some(value) => format!("--{name}={value}").into(),
+            None => format!("--{name}").into(),
+        };
+        if let Err(err) = check(&arg, command) {
+            anyhow::bail!("{}:{}: {err}", path.display(), index + 1);
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// Parses `arg` alone, which is only missing the URLs the command line has.
+fn check(arg: &OsString, command: &clap::Command) -> Result<(), String> {
+    let err = match command.clone().try_get_matches_from([OsString::from("hd"), arg.clone()]) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    match err.kind() {
+        ErrorKind::MissingRequiredArgument | ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => Ok(()),
+        // the first line, without the usage clap adds for the command line
+        _ => Err(err.to_string().lines().next().unwrap_or_default().trim_start_matches("error: ").to_string()),
+    }
+}
+
+/// Leaves out the options of the config file `args` that the `command_line` sets, or that
+/// conflict with one it sets, so `-H` replaces the headers of the config instead of adding
+/// to them and `--auto` replaces a configured `connection-count`.
+pub fn without_overridden(args: Vec<OsString>, command_line: &[OsString], mut command: clap::Command) -> Vec<OsString> {
+    command.build();
+    let Ok(matches) = command.clone().ignore_errors(true).try_get_matches_from(command_line) else {
+        return args;
+    };
+    let overridden = command.get_arguments()
+        .filter(|n| matches.value_source(n.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect::<Vec<_>>();
+    let is_overridden = |arg: &clap::Arg| overridden.iter().any(|n| {
+        n.get_id() == arg.get_id()
+            || command.get_arg_conflicts_with(n).iter().any(|m| m.get_id() == arg.get_id())
+            || command.get_arg_conflicts_with(arg).iter().any(|m| m.get_id() == n.get_id())
+    });
+    args.into_iter()
+        .filter(|arg| {
+            let arg = arg.to_string_lossy();
+            let name = arg.trim_start_matches("--").split('=').next().unwrap_or_default();
+            !command.get_arguments().any(|n| n.get_long() == Some(name) && is_overridden(n))
+        })
+        .collect()
+}
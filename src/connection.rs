@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use http_downloader::ChunkItem;
+
+// the speed of a connection is measured over this long
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+// a connection needs this long before its speed means anything
+const MIN_SPEED_TIME: Duration = Duration::from_secs(2);
+
+/// A connection of a running download and the chunk it downloads.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    /// The first and the last byte of the chunk.
+    pub range: (u64, u64),
+    pub downloaded_len: u64,
+    pub started_at: Instant,
+    /// Bytes per second, `None` while the connection is too new.
+    pub speed: Option<u64>,
+    /// How often the chunk was restarted, e.g. after a stall or an error.
+    pub retries: u32,
+}
+
+impl Connection {
+    pub fn remaining_len(&self) -> u64 {
+        (self.range.1 - self.range.0 + 1).saturating_sub(self.downloaded_len)
+    }
+}
+
+struct Samples {
+    chunk: Weak<ChunkItem>,
+    started_at: Instant,
+    range: (u64, u64),
+    /// `(when, downloaded_len)` over the last [`SPEED_WINDOW`].
+    samples: VecDeque<(Instant, u64)>,
+    retries: u32,
+}
+
+/// Tracks the speed of each connection from the progress of its chunk.
+#[derive(Default)]
+pub struct ConnectionTracker {
+    chunks: Mutex<HashMap<usize, Samples>>,
+    /// The retries of the chunks a stopped download left unfinished, by index.
+    interrupted: Mutex<HashMap<usize, u32>>,
+}
+
+impl ConnectionTracker {
+    /// Samples the chunks the download is running, forgetting the finished ones.
+    pub fn update(&self, chunks: &[Arc<ChunkItem>]) {
+        let now = Instant::now();
+        let mut tracked = self.chunks.lock().unwrap();
+        tracked.retain(|index, _| chunks.iter().any(|n| n.chunk_info.index == *index && !is_finished(n)));
+        for chunk in chunks.iter().filter(|n| !is_finished(n)) {
+            let range = (chunk.chunk_info.range.start, chunk.chunk_info.range.end);
+            let samples = tracked.entry(chunk.chunk_info.index).or_insert_with(|| Samples {
+                chunk: Arc::downgrade(chunk),
+                started_at: now,
+                range,
+                samples: VecDeque::new(),
+                retries: self.interrupted.lock().unwrap().remove(&chunk.chunk_info.index).map(|n| n + 1).unwrap_or(0),
+            });
+            samples.samples.push_back((now, chunk.downloaded_len.load(Ordering::Relaxed)));
+            while samples.samples.front().is_some_and(|(at, _)| now - *at > SPEED_WINDOW) {
+                samples.samples.pop_front();
+            }
+        }
+    }
+
+    /// Forgets all connections once the download stopped, remembering their chunks to
+    /// count a retry when the download resumes them.
+    pub fn stop(&self) {
+        let mut interrupted = self.interrupted.lock().unwrap();
+        for (index, samples) in self.chunks.lock().unwrap().drain() {
+            interrupted.insert(index, samples.retries);
+        }
+    }
+
+    /// The running connections in the order of their ranges.
+    pub fn connections(&self) -> Vec<Connection> {
+        let now = Instant::now();
+        let mut connections = self.chunks.lock().unwrap().values()
+            .map(|chunk| {
+                let (first_at, first_len) = chunk.samples.front().copied().unwrap_or((now, 0));
+                let (last_at, last_len) = chunk.samples.back().copied().unwrap_or((now, 0));
+                let speed = (now - chunk.started_at >= MIN_SPEED_TIME && last_at > first_at).then(|| {
+                    (last_len.saturating_sub(first_len) as f64 / (last_at - first_at).as_secs_f64()) as u64
+                });
+                Connection {
+                    range: chunk.range,
+                    // a weak reference, holding the chunk would keep the library waiting for it
+                    downloaded_len: chunk.chunk.upgrade().map(|n| n.downloaded_len.load(Ordering::Relaxed)).unwrap_or(last_len),
+                    started_at: chunk.started_at,
+                    speed,
+                    retries: chunk.retries,
+                }
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|n| n.range.0);
+        connections
+    }
+}
+
+fn is_finished(chunk: &ChunkItem) -> bool {
+    chunk.downloaded_len.load(Ordering::Relaxed) == chunk.chunk_info.range.len()
+}
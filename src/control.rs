@@ -0,0 +1,147 @@
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::download::Download;
+use crate::limit::{format_byte_count_per, format_speed_limit, GlobalSpeedLimiter, SpeedLimit};
+use crate::size;
+
+/// Lets scripts change a running `hd` through `--control-socket`, one command per line,
+/// each answered with one line:
+///
+/// ```text
+/// limit                   prints the speed limit of each download
+/// limit 2M | off          changes it
+/// global-limit [2M | off] the same for --global-speed-limit
+/// connections             prints the connection count of each download as `<#>=<count>`
+/// connections [#] 8       changes it for download number # or all of them
+/// ```
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+/// What the commands act on.
+#[derive(Clone)]
+pub struct Controls {
+    pub speed_limit: SpeedLimit,
+    pub global_speed_limiter: Arc<GlobalSpeedLimiter>,
+    pub downloads: Vec<Arc<Download>>,
+}
+
+impl ControlSocket {
+    /// Listens on a Unix socket at `path` until dropped, which removes the socket file.
+    #[cfg(unix)]
+    pub fn bind(path: PathBuf, controls: Controls) -> Result<Self> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        let listener = match UnixListener::bind(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse
+                && std::os::unix::net::UnixStream::connect(&path).is_err() => {
+                // left behind by a run that did not exit cleanly, anything else is not ours
+                if !is_socket(&path) {
+                    anyhow::bail!("{}: path exists and is not a socket", path.display());
+                }
+                std::fs::remove_file(&path)?;
+                UnixListener::bind(&path)
+            }
+            r => r,
+        }.map_err(|err| anyhow::Error::msg(format!("{}: {err}", path.display())))?;
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let controls = controls.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let reply = match execute(&line, &controls) {
+                            Ok(reply) => reply,
+                            Err(err) => format!("error: {err}"),
+                        };
+                        if writer.write_all(format!("{reply}\n").as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self { path })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(_path: PathBuf, _controls: Controls) -> Result<Self> {
+        anyhow::bail!("--control-socket is only supported on Unix")
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if is_socket(&self.path) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_socket(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::symlink_metadata(path).is_ok_and(|n| n.file_type().is_socket())
+}
+
+#[cfg(not(unix))]
+fn is_socket(_path: &Path) -> bool {
+    false
+}
+
+fn execute(line: &str, controls: &Controls) -> Result<String, String> {
+    let Controls { speed_limit, global_speed_limiter, downloads } = controls;
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (command, arguments) = words.split_first().map(|(n, m)| (*n, m)).unwrap_or_default();
+    match (command, arguments) {
+        ("limit", []) => {}
+        ("limit", ["off" | "none"]) => speed_limit.set(None),
+        ("limit", [value]) => speed_limit.set(Some(size::parse(value)?)),
+        ("global-limit", []) => {}
+        ("global-limit", ["off" | "none"]) => global_speed_limiter.set(None),
+        ("global-limit", [value]) => global_speed_limiter.set(Some(size::parse(value)?)),
+        ("connections", []) => {}
+        ("connections", [connection_count]) => {
+            let connection_count = parse_connection_count(connection_count)?;
+            for download in downloads.iter() {
+                download.set_connection_count(connection_count);
+            }
+        }
+        ("connections", [number, connection_count]) => {
+            let download = number.parse::<usize>().ok()
+                .and_then(|n| downloads.get(n.wrapping_sub(1)))
+                .ok_or_else(|| format!("no download number `{number}`"))?;
+            download.set_connection_count(parse_connection_count(connection_count)?);
+        }
+        ("limit" | "global-limit" | "connections", _) => return Err(format!("too many arguments for `{command}`")),
+        _ => return Err(format!(
+            "unknown command `{command}`, expected `limit [SIZE|off]`, `global-limit [SIZE|off]` or `connections [[#] COUNT]`"
+        )),
+    }
+    Ok(match command {
+        "global-limit" => format!("global-limit {}", format_byte_count_per(global_speed_limiter.get())),
+        "connections" => {
+            let counts = downloads.iter()
+                .enumerate()
+                .map(|(index, download)| format!("{}={}", index + 1, download.connection_count()))
+                .collect::<Vec<_>>();
+            format!("connections {}", counts.join(" "))
+        }
+        _ => format!("limit {}", format_speed_limit(speed_limit)),
+    })
+}
+
+fn parse_connection_count(value: &str) -> Result<NonZeroU8, String> {
+    value.parse().map_err(|_| format!("invalid connection count `{value}`, expected 1 to 255"))
+}
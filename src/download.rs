@@ -0,0 +1,966 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use http_downloader::{ChunkManager, DownloadWay, breakpoint_resume::DownloadBreakpointResumeExtension, DownloadController, DownloadExtension, DownloadingEndCause, DownloadError, DownloadStartError, DownloadStopError, ExtensibleHttpFileDownloader, HttpDownloadConfig, HttpFileDownloader, speed_limiter::{DownloadSpeedLimiterExtension, DownloadSpeedLimiterState}, speed_tracker::{DownloadSpeedTrackerExtension, DownloadSpeedTrackerState}};
+use http_downloader::bson_file_archiver::{ArchiveFilePath, BsonFileArchiverBuilder};
+use tokio::sync;
+use url::Url;
+
+use crate::Args;
+use crate::auto;
+use crate::checksum::{Checksum, ChecksumMismatchAction, HashAlgorithm};
+use crate::connection::{Connection, ConnectionTracker};
+use crate::extension::{CheckedArchiverBuilder, ChunkCheck, ChunkCheckExtension, ChunkSplitExtension, DownloadWayExtension, DownloadWayState, GlobalSpeedLimiterExtension};
+use crate::file_hasher::FileHasher;
+use crate::job::Job;
+use crate::limit::GlobalSpeedLimiter;
+use crate::retry::{ChunkLenError, HttpStatusError, IncompleteError, ReadTimeoutError, RetryPolicy};
+use crate::segment::{SegmentMap, SegmentState};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownloadStatus {
+    Queued,
+    Downloading,
+    Paused,
+    /// Waiting until `at` to retry after a transient error.
+    Retrying { attempt: u32, retries: u32, at: Instant, error: String },
+    Verifying,
+    Finished,
+    Cancelled,
+    Failed(String),
+}
+
+impl DownloadStatus {
+    pub fn is_end(&self) -> bool {
+        matches!(
+            self,
+            DownloadStatus::Finished | DownloadStatus::Cancelled | DownloadStatus::Failed(_)
+        )
+    }
+}
+
+impl Display for DownloadStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DownloadStatus::Queued => "Queued",
+            DownloadStatus::Downloading => "Downloading",
+            DownloadStatus::Paused => "Paused",
+            DownloadStatus::Retrying { attempt, retries, at, .. } => {
+                let seconds = at.saturating_duration_since(Instant::now()).as_secs_f64().ceil();
+                return write!(f, "Retrying in {seconds}s (attempt {attempt}/{retries})");
+            }
+            DownloadStatus::Verifying => "Verifying",
+            DownloadStatus::Finished => "Finished",
+            DownloadStatus::Cancelled => "Cancelled",
+            DownloadStatus::Failed(_) => "Failed",
+        })
+    }
+}
+
+/// What the user asked a download to do, see [`Download::toggle_pause`] and [`Download::stop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Stop,
+    StopAndDelete,
+}
+
+const ARCHIVE_SUFFIX: &str = "bson";
+// more connections do not split the remaining ranges any smaller
+pub const MIN_CHUNK_SIZE: usize = 256 * 1024;
+// how often the connections are checked for --lowest-speed-limit and idling
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// how often the connections and the segment map are sampled
+const CONNECTION_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+// connections are only compared with --lowest-speed-limit after connecting for this long
+const LOWEST_SPEED_GRACE: Duration = Duration::from_secs(10);
+// idle connections only take over a range that would take longer than this to finish
+const MIN_STEAL_TIME: Duration = Duration::from_secs(5);
+// how often the smoothed speed takes a sample, and how much weight a sample has
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const SPEED_SMOOTHING: f64 = 0.2;
+// the speed history keeps this many samples, one per SPEED_SAMPLE_INTERVAL
+const SPEED_HISTORY_LEN: usize = 600;
+
+/// How long a download has been downloading, and how much of it was already there
+/// from an earlier run.
+#[derive(Default)]
+struct DownloadTime {
+    resumed_len: Option<u64>,
+    /// Time spent downloading before `since`.
+    elapsed: Duration,
+    /// Since when the download is downloading.
+    since: Option<Instant>,
+}
+
+pub struct Download {
+    pub id: usize,
+    pub url: Url,
+    pub downloader: ExtensibleHttpFileDownloader,
+    pub speed_state: DownloadSpeedTrackerState,
+    pub status_receiver: sync::watch::Receiver<DownloadStatus>,
+    client: reqwest::Client,
+    status_sender: sync::watch::Sender<DownloadStatus>,
+    control_sender: sync::watch::Sender<Control>,
+    total_len: AtomicU64,
+    checksums: Vec<Checksum>,
+    checksum_mismatch_action: ChecksumMismatchAction,
+    print_hash: Vec<HashAlgorithm>,
+    file_hasher: Option<Arc<FileHasher>>,
+    digests: Mutex<Vec<(HashAlgorithm, String)>>,
+    download_way_state: DownloadWayState,
+    retry_policy: RetryPolicy,
+    read_timeout: Option<Duration>,
+    speed_limiter_state: DownloadSpeedLimiterState,
+    speed_limit: sync::watch::Receiver<Option<usize>>,
+    connection_count: sync::watch::Sender<NonZeroU8>,
+    /// The first and the last byte of the connection a rebalance takes work from.
+    split_range: sync::watch::Sender<Option<(u64, u64)>>,
+    chunk_size: sync::watch::Sender<NonZeroUsize>,
+    auto: bool,
+    connections: ConnectionTracker,
+    chunk_check: Arc<ChunkCheck>,
+    segment_map: Mutex<Option<SegmentMap>>,
+    lowest_speed_limit: Option<usize>,
+    global_speed_limiter: Arc<GlobalSpeedLimiter>,
+    download_time: Mutex<DownloadTime>,
+    smoothed_speed: AtomicU64,
+    speed_history: Mutex<VecDeque<u64>>,
+}
+
+impl Download {
+    pub fn new(
+        id: usize,
+        job: Job,
+        file_path: &Path,
+        client: reqwest::Client,
+        speed_limit: sync::watch::Receiver<Option<usize>>,
+        global_speed_limiter: Arc<GlobalSpeedLimiter>,
+        args: &Args,
+    ) -> Self {
+        let Job { url, header_map, checksums, .. } = job;
+        // --auto starts with one connection and adds more while that helps
+        let connection_count = sync::watch::channel(if args.auto { NonZeroU8::MIN } else { args.connection_count }).0;
+        let split_range = sync::watch::channel(None).0;
+        let chunk_check = Arc::new(ChunkCheck::default());
+        let (downloader, (speed_state, _, speed_limiter_state, _, _, download_way_state)) =
+            build_downloader(
+                client.clone(),
+                HttpDownloadConfig {
+                    download_connection_count: *connection_count.borrow(),
+                    chunk_size: args.chunk_size,
+                    file_name: file_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    url: Arc::new(url.clone()),
+                    save_dir: file_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                    etag: None,
+                    // the library skips a failed body read and leaves a hole in the chunk,
+                    // failing the download instead resumes it from the last saved state
+                    request_retry_count: 0,
+                    timeout: None,
+                    header_map,
+                },
+                (
+                    DownloadSpeedTrackerExtension { log: false },
+                    // the library only takes up to six extensions
+                    (
+                        GlobalSpeedLimiterExtension {
+                            limiter: global_speed_limiter.clone(),
+                        },
+                        ChunkCheckExtension {
+                            check: chunk_check.clone(),
+                        },
+                    ),
+                    DownloadSpeedLimiterExtension {
+                        byte_count_per: *speed_limit.borrow()
+                    },
+                    ChunkSplitExtension {
+                        connection_count: connection_count.subscribe(),
+                        split_range: Mutex::new(split_range.subscribe()),
+                    },
+                    DownloadBreakpointResumeExtension {
+                        download_archiver_builder: CheckedArchiverBuilder {
+                            inner: BsonFileArchiverBuilder::new(ArchiveFilePath::Suffix(ARCHIVE_SUFFIX)),
+                            check: chunk_check.clone(),
+                        },
+                    },
+                    DownloadWayExtension,
+                ),
+            );
+        let mut algorithms = Vec::new();
+        for algorithm in checksums.iter().map(|n| n.algorithm).chain(args.print_hash.iter().copied()) {
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+        let file_hasher = (!algorithms.is_empty()).then(|| Arc::new(FileHasher::new(file_path.to_path_buf(), algorithms)));
+        let (status_sender, status_receiver) = sync::watch::channel(DownloadStatus::Queued);
+        Self {
+            id,
+            url,
+            downloader,
+            speed_state,
+            status_receiver,
+            status_sender,
+            client,
+            control_sender: sync::watch::channel(Control::Run).0,
+            total_len: Default::default(),
+            checksums,
+            checksum_mismatch_action: args.on_checksum_mismatch,
+            print_hash: args.print_hash.clone(),
+            file_hasher,
+            digests: Default::default(),
+            download_way_state,
+            retry_policy: RetryPolicy {
+                retries: args.retries,
+                wait: args.retry_wait,
+                max_wait: args.retry_max_wait,
+                retry_on: args.retry_on.clone(),
+            },
+            read_timeout: args.read_timeout,
+            speed_limiter_state,
+            speed_limit,
+            connection_count,
+            split_range,
+            chunk_size: sync::watch::channel(args.chunk_size).0,
+            auto: args.auto,
+            connections: Default::default(),
+            chunk_check,
+            segment_map: Default::default(),
+            lowest_speed_limit: args.lowest_speed_limit.filter(|n| *n > 0),
+            global_speed_limiter,
+            download_time: Default::default(),
+            smoothed_speed: Default::default(),
+            speed_history: Default::default(),
+        }
+    }
+
+    pub fn status(&self) -> DownloadStatus {
+        self.status_receiver.borrow().clone()
+    }
+
+    pub fn file_path(&self) -> PathBuf {
+        self.downloader.get_file_path()
+    }
+
+    pub fn file_name(&self) -> String {
+        self.downloader.config().file_name.clone()
+    }
+
+    pub fn downloaded_len(&self) -> u64 {
+        self.downloader.downloaded_len()
+    }
+
+    /// The speed tracker keeps its last value once a download stopped.
+    pub fn speed(&self) -> u64 {
+        if self.status() == DownloadStatus::Downloading { self.speed_state.download_speed() } else { 0 }
+    }
+
+    /// Exponentially smoothed speed, steadier than [`Download::speed`].
+    pub fn smoothed_speed(&self) -> u64 {
+        if self.status() == DownloadStatus::Downloading { self.smoothed_speed.load(Ordering::Relaxed) } else { 0 }
+    }
+
+    /// Estimated time left, `None` while it cannot be told.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining_len = self.total_len()?.saturating_sub(self.downloaded_len());
+        match self.smoothed_speed() {
+            0 => None,
+            speed => Some(Duration::from_secs(remaining_len.div_ceil(speed))),
+        }
+    }
+
+    /// The speed of the speed tracker once a second, oldest first, since the download
+    /// first started. Pauses, stalls and retries are in there as zeros.
+    pub fn speed_history(&self) -> Vec<u64> {
+        self.speed_history.lock().unwrap().iter().copied().collect()
+    }
+
+    /// The speed limit of this download, or --global-speed-limit if that is lower.
+    pub fn speed_limit(&self) -> Option<usize> {
+        match (*self.speed_limit.borrow(), self.global_speed_limiter.get()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Time spent downloading in this run, without pauses and waiting for retries.
+    pub fn download_time(&self) -> Duration {
+        let download_time = self.download_time.lock().unwrap();
+        download_time.elapsed + download_time.since.map(|n| n.elapsed()).unwrap_or_default()
+    }
+
+    /// What an earlier run had already downloaded.
+    pub fn resumed_len(&self) -> u64 {
+        self.download_time.lock().unwrap().resumed_len.unwrap_or(0)
+    }
+
+    /// Speed over the whole [`Download::download_time`], without the resumed bytes.
+    pub fn average_speed(&self) -> u64 {
+        let download_time = self.download_time().as_secs_f64();
+        if download_time < SPEED_SAMPLE_INTERVAL.as_secs_f64() {
+            return 0;
+        }
+        (self.downloaded_len().saturating_sub(self.resumed_len()) as f64 / download_time) as u64
+    }
+
+    /// `None` until the server response arrived or if it has no `Content-Length`.
+    pub fn total_len(&self) -> Option<u64> {
+        match self.total_len.load(Ordering::Relaxed) {
+            0 => None,
+            total_len => Some(total_len),
+        }
+    }
+
+    /// How much of the file has been hashed so far.
+    pub fn verified_len(&self) -> u64 {
+        self.file_hasher.as_ref().map(|n| n.hashed_len()).unwrap_or(0)
+    }
+
+    /// The --print-hash digests, available once the download finished.
+    pub fn digests(&self) -> Vec<(HashAlgorithm, String)> {
+        self.digests.lock().unwrap().clone()
+    }
+
+    /// The breakpoint resume data saved next to the file.
+    pub fn archive_file_path(&self) -> PathBuf {
+        ArchiveFilePath::Suffix(ARCHIVE_SUFFIX).get_file_path(&self.file_path())
+    }
+
+    /// The connections of the running download in the order of their ranges, connection
+    /// number `n` of the segment map is the `n`th.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.connections.connections()
+    }
+
+    /// What is done, pending and left to each connection, `None` before the download
+    /// started with a known size and once it finished.
+    pub fn segment_map(&self) -> Option<SegmentMap> {
+        self.segment_map.lock().unwrap().clone()
+    }
+
+    pub fn connection_count(&self) -> NonZeroU8 {
+        *self.connection_count.borrow()
+    }
+
+    /// Adds or drops connections of the running download, and of any later restart of it.
+    /// Dropped connections end once their current chunk is done.
+    pub fn set_connection_count(&self, connection_count: NonZeroU8) {
+        self.connection_count.send_replace(connection_count);
+    }
+
+    pub fn chunk_size(&self) -> NonZeroUsize {
+        *self.chunk_size.borrow()
+    }
+
+    /// The largest chunk the running download, and any later restart of it, requests.
+    pub fn set_chunk_size(&self, chunk_size: NonZeroUsize) {
+        self.chunk_size.send_replace(chunk_size);
+    }
+
+    /// Whether --auto picks the connection count and chunk size.
+    pub fn is_auto(&self) -> bool {
+        self.auto
+    }
+
+    fn set_status(&self, status: DownloadStatus) {
+        let mut download_time = self.download_time.lock().unwrap();
+        match (status == DownloadStatus::Downloading, download_time.since) {
+            (true, None) => download_time.since = Some(Instant::now()),
+            (false, Some(since)) => {
+                download_time.elapsed += since.elapsed();
+                download_time.since = None;
+            }
+            _ => {}
+        }
+        drop(download_time);
+        self.status_sender.send_replace(status);
+    }
+
+    /// Pauses a queued or running download, or resumes a paused one. A paused
+    /// download gives its slot to the next queued one.
+    pub fn toggle_pause(&self) {
+        self.control_sender.send_if_modified(|control| {
+            *control = match control {
+                Control::Run => Control::Pause,
+                Control::Pause => Control::Run,
+                Control::Stop | Control::StopAndDelete => return false,
+            };
+            true
+        });
+    }
+
+    /// Stops the download for this run, the breakpoint resume data is kept so the
+    /// next run continues where it stopped. With `delete_file` the partial file and
+    /// resume data are removed instead.
+    pub fn stop(&self, delete_file: bool) {
+        if delete_file && self.status() == DownloadStatus::Cancelled {
+            self.delete_file();
+            return;
+        }
+        self.control_sender.send_if_modified(|control| {
+            let new_control = if delete_file { Control::StopAndDelete } else { Control::Stop };
+            if *control == new_control || *control == Control::StopAndDelete {
+                return false;
+            }
+            *control = new_control;
+            true
+        });
+    }
+
+    /// Removes the resume data of a download that did not stop in time, its chunks may
+    /// still be writing bytes it already counts as downloaded.
+    pub fn abandon(&self) {
+        if !self.status().is_end() {
+            let _ = std::fs::remove_file(self.archive_file_path());
+        }
+    }
+
+    fn delete_file(&self) {
+        let _ = std::fs::remove_file(self.file_path());
+        let _ = std::fs::remove_file(self.archive_file_path());
+    }
+
+    /// Runs the download to the end, taking a permit of `slots` whenever it is not paused.
+    pub async fn run(&self, slots: &sync::Semaphore) -> Result<DownloadingEndCause> {
+        let mut result = tokio::select! {
+            r = self.run_until_end(slots) => r,
+            _ = auto::tune(self), if self.auto => unreachable!(),
+            _ = self.record_speed_history() => unreachable!(),
+        };
+        if let Ok(DownloadingEndCause::DownloadFinished) = result {
+            result = self.check_len().map(|_| DownloadingEndCause::DownloadFinished);
+        }
+        if let Ok(DownloadingEndCause::DownloadFinished) = result {
+            *self.segment_map.lock().unwrap() = None;
+            if self.file_hasher.is_some() {
+                self.set_status(DownloadStatus::Verifying);
+                result = self.verify().await.map(|_| DownloadingEndCause::DownloadFinished);
+            }
+        }
+        if result.is_ok() && *self.control_sender.borrow() == Control::StopAndDelete {
+            self.delete_file();
+        }
+        self.set_status(match &result {
+            Ok(DownloadingEndCause::DownloadFinished) => DownloadStatus::Finished,
+            Ok(DownloadingEndCause::Cancelled) => DownloadStatus::Cancelled,
+            Err(err) => DownloadStatus::Failed(error_message(err)),
+        });
+        result
+    }
+
+    async fn record_speed_history(&self) {
+        loop {
+            tokio::time::sleep(SPEED_SAMPLE_INTERVAL).await;
+            let mut speed_history = self.speed_history.lock().unwrap();
+            // a queued download has no history yet
+            if speed_history.is_empty() && self.status() != DownloadStatus::Downloading {
+                continue;
+            }
+            if speed_history.len() == SPEED_HISTORY_LEN {
+                speed_history.pop_front();
+            }
+            speed_history.push_back(self.speed());
+        }
+    }
+
+    /// Transient errors are retried with backoff, a download that got further than at its
+    /// previous failure starts counting its retries again.
+    async fn run_until_end(&self, slots: &sync::Semaphore) -> Result<DownloadingEndCause> {
+        let mut control_receiver = self.control_sender.subscribe();
+        let (mut attempt, mut failed_at_len) = (0, 0);
+        loop {
+            let control = *control_receiver.borrow_and_update();
+            match control {
+                Control::Run => {}
+                Control::Pause => {
+                    self.set_status(DownloadStatus::Paused);
+                    control_receiver.changed().await?;
+                    continue;
+                }
+                Control::Stop | Control::StopAndDelete => return Ok(DownloadingEndCause::Cancelled),
+            }
+            self.set_status(DownloadStatus::Queued);
+            let permit = tokio::select! {
+                permit = slots.acquire() => permit?,
+                r = control_receiver.changed() => {
+                    r?;
+                    continue;
+                }
+            };
+
+            self.set_status(DownloadStatus::Downloading);
+            let err = match self.download().await {
+                Ok(DownloadingEndCause::Cancelled) if matches!(*self.control_sender.borrow(), Control::Run | Control::Pause) => continue,
+                Ok(dec) => return Ok(dec),
+                Err(err) => err,
+            };
+            drop(permit);
+
+            // what came back for a chunk with the wrong length is no progress
+            let downloaded_len = self.downloaded_len().saturating_sub(self.chunk_check.wrong_len());
+            if downloaded_len > failed_at_len {
+                attempt = 0;
+            }
+            failed_at_len = downloaded_len;
+            attempt += 1;
+            let Some(delay) = self.retry_policy.delay(&err, attempt) else {
+                return Err(err);
+            };
+            self.set_status(DownloadStatus::Retrying {
+                attempt,
+                retries: self.retry_policy.retries,
+                at: Instant::now() + delay,
+                error: error_message(&err),
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                r = control_receiver.changed() => r?,
+            }
+        }
+    }
+
+    async fn verify(&self) -> Result<()> {
+        let Some(file_hasher) = self.file_hasher.clone() else {
+            return Ok(());
+        };
+        let digests = {
+            let file_hasher = file_hasher.clone();
+            tokio::task::spawn_blocking(move || file_hasher.finish()).await??
+        };
+        let digests = file_hasher.algorithms().iter().copied().zip(digests).collect::<Vec<_>>();
+        *self.digests.lock().unwrap() = digests.iter()
+            .filter(|(algorithm, _)| self.print_hash.contains(algorithm))
+            .cloned()
+            .collect();
+
+        for checksum in self.checksums.iter() {
+            let Some((_, digest)) = digests.iter().find(|(algorithm, _)| *algorithm == checksum.algorithm) else {
+                continue;
+            };
+            if checksum.expected != *digest {
+                if self.checksum_mismatch_action == ChecksumMismatchAction::Delete {
+                    let _ = std::fs::remove_file(self.file_path());
+                }
+                anyhow::bail!("{} checksum mismatch, expected {} but got {}", checksum.algorithm, checksum.expected, digest);
+            }
+        }
+        Ok(())
+    }
+
+    /// The library saves whatever the server answers, so the status is checked first,
+    /// asking for the byte at `start`.
+    async fn check_status(&self, start: u64) -> Result<()> {
+        let config = self.downloader.config();
+        let mut request = self.client
+            .get(config.url.as_str())
+            .headers(config.header_map.clone())
+            .header(reqwest::header::RANGE, format!("bytes={start}-{start}"));
+        if let Some(read_timeout) = self.read_timeout {
+            request = request.timeout(read_timeout);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(HttpStatusError::new(response.status(), response.headers()).into());
+        }
+        Ok(())
+    }
+
+    /// Why a chunk did not receive its `range`, the status the server now answers for it
+    /// if that is an error.
+    async fn chunk_error(&self, range: Range<u64>) -> anyhow::Error {
+        match self.check_status(range.start).await {
+            Ok(()) => ChunkLenError(range).into(),
+            Err(err) => err,
+        }
+    }
+
+    /// Fails a download that left a range with something else than the server's file,
+    /// the resume data has it to download again.
+    async fn check_chunks(&self) -> Result<()> {
+        let range = self.chunk_check.bad_ranges().borrow().first().cloned();
+        match range {
+            Some(range) => Err(self.chunk_error(range).await),
+            None => Ok(()),
+        }
+    }
+
+    /// A finished download has to have all of the file, whatever the library counted as done.
+    fn check_len(&self) -> Result<()> {
+        match (self.total_len(), self.downloaded_len()) {
+            (Some(total_len), downloaded_len) if downloaded_len != total_len => {
+                Err(IncompleteError { downloaded_len, total_len }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn download(&self) -> Result<DownloadingEndCause> {
+        let mut control_receiver = self.control_sender.subscribe();
+        tokio::select! {
+            r = self.check_status(0) => r?,
+            _ = wait_for_stop(&mut control_receiver) => return Ok(DownloadingEndCause::Cancelled),
+        }
+        loop {
+            let result = self.run_downloader(&mut control_receiver).await;
+            if let Some(segment_map) = self.segment_map.lock().unwrap().as_mut() {
+                match result {
+                    Ok(_) => segment_map.stop(),
+                    Err(_) => segment_map.fail(self.connections.connections().iter().map(|n| n.range.0..n.range.1 + 1)),
+                }
+            }
+            self.connections.stop();
+            match result {
+                Ok(Some(dec)) => return Ok(dec),
+                Ok(None) if *self.control_sender.borrow() == Control::Run => continue,
+                Ok(None) => return Ok(DownloadingEndCause::Cancelled),
+                Err(err) => {
+                    // stops the other chunks, the downloader also refuses to start again until cancelled
+                    let _ = self.downloader.cancel().await;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// `None` when the download was cancelled to rebalance its connections and has to be
+    /// resumed right away.
+    async fn run_downloader(&self, control_receiver: &mut sync::watch::Receiver<Control>) -> Result<Option<DownloadingEndCause>> {
+        let mut finished_future = Box::pin(self.downloader.start().await?);
+        // `total_size` only resolves once the finished future is being polled
+        let total_len_future = async {
+            let total_len = self.downloader.total_size().await;
+            self.total_len.store(total_len.unwrap_or(0), Ordering::Relaxed);
+            std::future::pending::<()>().await
+        };
+        let (is_stalled, is_rebalancing) = (AtomicBool::new(false), AtomicBool::new(false));
+        let stop_future = async {
+            tokio::select! {
+                _ = wait_for_stop(control_receiver) => {}
+                _ = self.wait_for_stall() => {
+                    if self.download_way_state.receiver.borrow().is_none() {
+                        // still waiting for the response, there is nothing to save yet
+                        return;
+                    }
+                    is_stalled.store(true, Ordering::Relaxed);
+                }
+                range = self.wait_for_rebalance() => {
+                    self.split_range.send_replace(Some(range));
+                    is_rebalancing.store(true, Ordering::Relaxed);
+                }
+            }
+            // cancelling before the download has really started is lost, so retry until it sticks
+            while let Err(DownloadStopError::NoStart) = self.downloader.cancel().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            std::future::pending::<()>().await
+        };
+        let hash_future = async {
+            match &self.file_hasher {
+                Some(file_hasher) => file_hasher.clone().follow(self.download_way_state.receiver.clone()).await,
+                None => std::future::pending::<()>().await,
+            }
+        };
+        let speed_future = async {
+            let mut download_way = self.download_way_state.receiver.clone();
+            while download_way.borrow_and_update().is_none() {
+                if download_way.changed().await.is_err() {
+                    std::future::pending::<()>().await
+                }
+            }
+            // the progress of a resumed download is known once it started
+            let mut last_len = self.downloaded_len();
+            self.download_time.lock().unwrap().resumed_len.get_or_insert(last_len);
+            loop {
+                tokio::time::sleep(SPEED_SAMPLE_INTERVAL).await;
+                let downloaded_len = self.downloaded_len();
+                let sample = downloaded_len.saturating_sub(last_len) as f64 / SPEED_SAMPLE_INTERVAL.as_secs_f64();
+                last_len = downloaded_len;
+                let _ = self.smoothed_speed.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |speed| {
+                    Some(if speed == 0 { sample } else { speed as f64 + (sample - speed as f64) * SPEED_SMOOTHING } as u64)
+                });
+            }
+        };
+        let speed_limit_future = async {
+            let mut speed_limit = self.speed_limit.clone();
+            loop {
+                let byte_count_per = *speed_limit.borrow_and_update();
+                self.speed_limiter_state.change_speed(byte_count_per).await;
+                if speed_limit.changed().await.is_err() {
+                    std::future::pending::<()>().await
+                }
+            }
+        };
+        let connections_future = async {
+            let mut connection_count = self.connection_count.subscribe();
+            let mut chunk_size = self.chunk_size.subscribe();
+            let mut download_way = self.download_way_state.receiver.clone();
+            loop {
+                if let Some(DownloadWay::Ranges(chunk_manager)) = download_way.borrow_and_update().as_deref() {
+                    self.apply_connections(chunk_manager, *connection_count.borrow_and_update(), *chunk_size.borrow_and_update());
+                }
+                let r = tokio::select! {
+                    r = connection_count.changed() => r,
+                    r = chunk_size.changed() => r,
+                    r = download_way.changed() => r,
+                };
+                if r.is_err() {
+                    std::future::pending::<()>().await
+                }
+            }
+        };
+        let sample_future = async {
+            loop {
+                tokio::time::sleep(CONNECTION_SAMPLE_INTERVAL).await;
+                self.sample_connections().await;
+            }
+        };
+        // with no other chunk running the library misses that the last one finished, which
+        // is always the case with a single connection
+        let completion_future = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let download_way = self.download_way_state.receiver.borrow().clone();
+                if let Some(DownloadWay::Ranges(chunk_manager)) = download_way.as_deref() {
+                    let remaining_len = chunk_manager.chunk_iterator.data.lock().remaining_len();
+                    if remaining_len == 0 && chunk_manager.get_chunks().await.is_empty() {
+                        return;
+                    }
+                }
+            }
+        };
+        let chunk_check_future = async {
+            let mut bad_ranges = self.chunk_check.bad_ranges();
+            loop {
+                if let Some(range) = bad_ranges.borrow_and_update().first().cloned() {
+                    return range;
+                }
+                if bad_ranges.changed().await.is_err() {
+                    std::future::pending::<()>().await
+                }
+            }
+        };
+        let read_timeout_err = || ReadTimeoutError(self.read_timeout.unwrap_or_default()).into();
+        tokio::select! {
+            dec = &mut finished_future => match dec? {
+                // the cancelled download saved its progress, the restart reconnects
+                DownloadingEndCause::Cancelled if is_stalled.load(Ordering::Relaxed) => Err(read_timeout_err()),
+                DownloadingEndCause::Cancelled if is_rebalancing.load(Ordering::Relaxed) => Ok(None),
+                DownloadingEndCause::DownloadFinished => {
+                    self.check_chunks().await?;
+                    self.check_len()?;
+                    Ok(Some(DownloadingEndCause::DownloadFinished))
+                }
+                dec => Ok(Some(dec)),
+            },
+            _ = total_len_future => unreachable!(),
+            _ = stop_future => Err(read_timeout_err()),
+            _ = hash_future => unreachable!(),
+            _ = speed_future => unreachable!(),
+            _ = speed_limit_future => unreachable!(),
+            _ = connections_future => unreachable!(),
+            _ = sample_future => unreachable!(),
+            range = chunk_check_future => {
+                // the resume data keeps the bad ranges, and has the chunks that ended with
+                // them checked, once the cancelled download ended
+                let _ = self.downloader.cancel().await;
+                let _ = finished_future.await;
+                Err(self.chunk_error(range).await)
+            }
+            _ = completion_future => {
+                let _ = self.downloader.cancel().await;
+                finished_future.await?;
+                self.check_chunks().await?;
+                // the archive resumes whatever is missing
+                self.check_len()?;
+                let _ = std::fs::remove_file(self.archive_file_path());
+                Ok(Some(DownloadingEndCause::DownloadFinished))
+            }
+        }
+    }
+
+    /// Splits the ranges no connection downloads yet into chunks of at most `chunk_size`,
+    /// small enough that each of `connection_count` connections gets one.
+    fn apply_connections(&self, chunk_manager: &ChunkManager, connection_count: NonZeroU8, chunk_size: NonZeroUsize) {
+        let config = self.downloader.config();
+        let is_config = connection_count == config.download_connection_count && chunk_size == config.chunk_size;
+        if is_config && chunk_manager.connection_count() == connection_count.get() {
+            return;
+        }
+        let remaining_len = chunk_manager.chunk_iterator.data.lock().remaining.ranges.iter().map(|n| n.len()).sum::<u64>();
+        let split_size = remaining_len.div_ceil(u64::from(connection_count.get())) as usize;
+        let split_size = split_size.clamp(MIN_CHUNK_SIZE, chunk_size.get().max(MIN_CHUNK_SIZE));
+        chunk_manager.change_chunk_size(NonZeroUsize::new(split_size).unwrap());
+        if chunk_manager.connection_count() != connection_count.get() {
+            let _ = chunk_manager.change_connection_count(connection_count);
+        }
+    }
+
+    /// Samples the speed of the connections, and what they have left for the segment map.
+    async fn sample_connections(&self) {
+        let Some(total_len) = self.total_len() else {
+            return;
+        };
+        let download_way = self.download_way_state.receiver.borrow().clone();
+        let mut segment_map = SegmentMap::new(total_len);
+        match download_way.as_deref() {
+            Some(DownloadWay::Ranges(chunk_manager)) => {
+                self.connections.update(&chunk_manager.get_chunks().await);
+                {
+                    let data = chunk_manager.chunk_iterator.data.lock();
+                    let pending = data.remaining.ranges.iter().chain(data.last_incomplete_chunks.iter().map(|n| &n.range));
+                    for range in pending {
+                        segment_map.add(range.start..range.end + 1, SegmentState::Pending);
+                    }
+                }
+                for (index, connection) in self.connections.connections().iter().enumerate() {
+                    let start = connection.range.0 + connection.downloaded_len;
+                    segment_map.add(start..connection.range.1 + 1, SegmentState::Downloading(index + 1));
+                }
+            }
+            Some(DownloadWay::Single(_)) => segment_map.add(self.downloaded_len()..total_len, SegmentState::Downloading(1)),
+            None => return,
+        }
+        *self.segment_map.lock().unwrap() = Some(segment_map);
+    }
+
+    /// Resolves with the range of a connection slower than --lowest-speed-limit, or of one
+    /// with a long way to go while there are idle connections. The caller then restarts
+    /// the download like after a stall, which splits that range and resumes the other
+    /// connections where they were. The library cannot reconnect a single connection.
+    async fn wait_for_rebalance(&self) -> (u64, u64) {
+        loop {
+            tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
+            let download_way = self.download_way_state.receiver.borrow().clone();
+            let Some(DownloadWay::Ranges(chunk_manager)) = download_way.as_deref() else {
+                continue;
+            };
+            let connections = self.connections.connections();
+            let is_unassigned_len = chunk_manager.chunk_iterator.data.lock().remaining_len() > 0;
+            let connection = self.slow_connection(&connections)
+                .or_else(|| if is_unassigned_len { None } else { self.stealable_connection(&connections) });
+            if let Some(connection) = connection {
+                return connection.range;
+            }
+        }
+    }
+
+    /// A connection that has been below --lowest-speed-limit for a while. Speed limits
+    /// slow down the connections on purpose, so there is none while one is set.
+    fn slow_connection<'a>(&self, connections: &'a [Connection]) -> Option<&'a Connection> {
+        let lowest_speed_limit = self.lowest_speed_limit? as u64;
+        if self.speed_limit.borrow().is_some() || self.global_speed_limiter.get().is_some() {
+            return None;
+        }
+        connections.iter().find(|n| {
+            n.started_at.elapsed() >= LOWEST_SPEED_GRACE && n.speed.is_some_and(|speed| speed < lowest_speed_limit)
+        })
+    }
+
+    /// The connection taking longest to finish its range while some connections have
+    /// nothing left to download, if splitting its range is worth a restart.
+    fn stealable_connection<'a>(&self, connections: &'a [Connection]) -> Option<&'a Connection> {
+        if connections.len() >= usize::from(self.connection_count().get()) {
+            return None;
+        }
+        connections.iter()
+            .filter(|n| n.remaining_len() >= 2 * MIN_CHUNK_SIZE as u64)
+            .filter_map(|n| Some((n, n.remaining_len() as f64 / n.speed? as f64)))
+            .filter(|(_, seconds)| *seconds > MIN_STEAL_TIME.as_secs_f64())
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(n, _)| n)
+    }
+
+    /// Resolves once a connection received nothing for --read-timeout. The library cannot
+    /// restart a single chunk, so the caller cancels the download which keeps the progress
+    /// of every chunk, and resumes it.
+    async fn wait_for_stall(&self) {
+        let Some(read_timeout) = self.read_timeout else {
+            return std::future::pending().await;
+        };
+        let mut chunk_progress = HashMap::new();
+        let mut progress = (self.downloaded_len(), Instant::now());
+        loop {
+            tokio::time::sleep((read_timeout / 4).min(Duration::from_secs(1))).await;
+            let now = Instant::now();
+            let download_way = self.download_way_state.receiver.borrow().clone();
+            if let Some(DownloadWay::Ranges(chunk_manager)) = download_way.as_deref() {
+                let chunks = chunk_manager.get_chunks().await;
+                chunk_progress.retain(|index, _| chunks.iter().any(|n| n.chunk_info.index == *index));
+                for chunk in chunks {
+                    let downloaded_len = chunk.downloaded_len.load(Ordering::Relaxed);
+                    if downloaded_len == chunk.chunk_info.range.len() {
+                        continue;
+                    }
+                    let (len, at) = chunk_progress.entry(chunk.chunk_info.index).or_insert((downloaded_len, now));
+                    if *len != downloaded_len {
+                        (*len, *at) = (downloaded_len, now);
+                    } else if now - *at >= read_timeout {
+                        return;
+                    }
+                }
+            } else {
+                let downloaded_len = self.downloaded_len();
+                if progress.0 != downloaded_len {
+                    progress = (downloaded_len, now);
+                } else if now - progress.1 >= read_timeout {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Resolves once the control is no longer [`Control::Run`].
+async fn wait_for_stop(control_receiver: &mut sync::watch::Receiver<Control>) {
+    loop {
+        if *control_receiver.borrow_and_update() != Control::Run {
+            return;
+        }
+        if control_receiver.changed().await.is_err() {
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+/// The library formats the errors it wraps with `{:?}`.
+fn error_message(err: &anyhow::Error) -> String {
+    let source: &dyn std::error::Error = match (err.downcast_ref::<DownloadError>(), err.downcast_ref::<DownloadStartError>()) {
+        (Some(DownloadError::HttpRequestFailed(err)), _) | (_, Some(DownloadStartError::HttpRequestFailed(err))) => err,
+        (Some(DownloadError::IoError(err)), _) | (_, Some(DownloadStartError::FileCrateFailed(err))) => err,
+        _ => return err.to_string(),
+    };
+    let mut message = source.to_string();
+    let mut source = source.source();
+    while let Some(err) = source {
+        // some errors already include their source in the message
+        let err_message = err.to_string();
+        if !message.contains(&err_message) {
+            message.push_str(": ");
+            message.push_str(&err_message);
+        }
+        source = err.source();
+    }
+    message
+}
+
+// Same as `HttpDownloaderBuilder::build`, which has no way to choose the file name.
+fn build_downloader<
+    DC: DownloadController + 'static,
+    DE: DownloadExtension<HttpFileDownloader, DownloadController=DC>,
+>(
+    client: reqwest::Client,
+    config: HttpDownloadConfig,
+    extension: DE,
+) -> (ExtensibleHttpFileDownloader, DE::ExtensionState) {
+    let downloader = Arc::new(HttpFileDownloader::new(client, Box::new(config)));
+    let (ec, es) = extension.layer(downloader.clone(), downloader.clone());
+    (ExtensibleHttpFileDownloader::new(downloader, ec), es)
+}
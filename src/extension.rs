@@ -0,0 +1,768 @@
+use std::iter;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use http_downloader::{ChunkData, ChunkInfo, ChunkItem, ChunkManager, ChunkRange, DownloadArchiveData, DownloadController, DownloadedLenChangeNotify, DownloadError, DownloadExtension, DownloadingEndCause, DownloadParams, DownloadStartError, DownloadStopError, DownloadWay, HttpDownloadConfig, HttpFileDownloader, RemainingChunks};
+use http_downloader::breakpoint_resume::{DownloadDataArchiver, DownloadDataArchiverBuilder};
+use tokio::{select, sync};
+
+use crate::download::MIN_CHUNK_SIZE;
+use crate::limit::GlobalSpeedLimiter;
+
+/// Publishes the [`DownloadWay`] of the running download, `None` while it is not running.
+/// A cancelled download also only ends once its chunks wrote what they received.
+pub struct DownloadWayExtension;
+
+pub struct DownloadWayState {
+    pub receiver: sync::watch::Receiver<Option<Arc<DownloadWay>>>,
+}
+
+impl<DC: DownloadController> DownloadExtension<DC> for DownloadWayExtension {
+    type DownloadController = DownloadWayController<DC>;
+    type ExtensionState = DownloadWayState;
+
+    fn layer(
+        self,
+        _downloader: Arc<HttpFileDownloader>,
+        inner: Arc<DC>,
+    ) -> (Arc<Self::DownloadController>, Self::ExtensionState) {
+        let (sender, receiver) = sync::watch::channel(None);
+        (
+            Arc::new(DownloadWayController {
+                inner,
+                download_way_sender: Arc::new(sender),
+            }),
+            DownloadWayState { receiver },
+        )
+    }
+}
+
+pub struct DownloadWayController<DC: DownloadController> {
+    inner: Arc<DC>,
+    download_way_sender: Arc<sync::watch::Sender<Option<Arc<DownloadWay>>>>,
+}
+
+#[async_trait]
+impl<DC: DownloadController> DownloadController for DownloadWayController<DC> {
+    async fn download(
+        self: Arc<Self>,
+        mut params: DownloadParams,
+    ) -> Result<BoxFuture<'static, Result<DownloadingEndCause, DownloadError>>, DownloadStartError> {
+        let (sender, receiver) = sync::oneshot::channel();
+        params.download_way_oneshot_vec.push(sender);
+        let download_future = self.inner.to_owned().download(params).await?;
+
+        let download_way_sender = self.download_way_sender.clone();
+        Ok(async move {
+            let future = async {
+                if let Ok(download_way) = receiver.await {
+                    download_way_sender.send_replace(Some(download_way));
+                }
+                std::future::pending::<()>().await
+            };
+            let r = select! {
+                r = download_future => r,
+                _ = future => unreachable!(),
+            };
+            let download_way = download_way_sender.send_replace(None);
+            if let (Ok(DownloadingEndCause::Cancelled), Some(DownloadWay::Ranges(chunk_manager))) = (&r, download_way.as_deref()) {
+                let chunks = chunk_manager.get_chunks().await.iter().map(Arc::downgrade).collect::<Vec<_>>();
+                let chunk_manager = Arc::downgrade(chunk_manager);
+                drop(download_way);
+                wait_for_chunks(chunk_manager, chunks).await;
+            }
+            r
+        }.boxed())
+    }
+
+    async fn cancel(&self) -> Result<(), DownloadStopError> {
+        self.inner.cancel().await
+    }
+}
+
+/// The download ends as soon as it is cancelled, while its chunks still write what they
+/// received in their own tasks. The saved progress counts those bytes, so exiting before
+/// they are written leaves holes in the file.
+async fn wait_for_chunks(chunk_manager: Weak<ChunkManager>, chunks: Vec<Weak<ChunkItem>>) {
+    const MAX_WAIT: Duration = Duration::from_secs(10);
+
+    let start = Instant::now();
+    // the chunk manager keeps one reference to each chunk, the task of a chunk another
+    while start.elapsed() < MAX_WAIT {
+        let manager_count = if chunk_manager.strong_count() > 0 { 1 } else { 0 };
+        if chunks.iter().all(|n| n.strong_count() <= manager_count) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Makes the download draw from a shared [`GlobalSpeedLimiter`] after its own speed limit.
+/// Has to be layered inside `DownloadSpeedLimiterExtension`, which replaces the notify.
+pub struct GlobalSpeedLimiterExtension {
+    pub limiter: Arc<GlobalSpeedLimiter>,
+}
+
+impl<DC: DownloadController> DownloadExtension<DC> for GlobalSpeedLimiterExtension {
+    type DownloadController = GlobalSpeedLimiterController<DC>;
+    type ExtensionState = ();
+
+    fn layer(
+        self,
+        _downloader: Arc<HttpFileDownloader>,
+        inner: Arc<DC>,
+    ) -> (Arc<Self::DownloadController>, Self::ExtensionState) {
+        (
+            Arc::new(GlobalSpeedLimiterController {
+                inner,
+                limiter: self.limiter,
+                turn: Default::default(),
+            }),
+            (),
+        )
+    }
+}
+
+pub struct GlobalSpeedLimiterController<DC: DownloadController> {
+    inner: Arc<DC>,
+    limiter: Arc<GlobalSpeedLimiter>,
+    turn: Arc<sync::Mutex<()>>,
+}
+
+#[async_trait]
+impl<DC: DownloadController> DownloadController for GlobalSpeedLimiterController<DC> {
+    async fn download(
+        self: Arc<Self>,
+        mut params: DownloadParams,
+    ) -> Result<BoxFuture<'static, Result<DownloadingEndCause, DownloadError>>, DownloadStartError> {
+        params.downloaded_len_change_notify = Some(Arc::new(GlobalSpeedLimiterNotify {
+            inner: params.downloaded_len_change_notify.take(),
+            limiter: self.limiter.clone(),
+            turn: self.turn.clone(),
+        }));
+        self.inner.to_owned().download(params).await
+    }
+
+    async fn cancel(&self) -> Result<(), DownloadStopError> {
+        self.inner.cancel().await
+    }
+}
+
+struct GlobalSpeedLimiterNotify {
+    inner: Option<Arc<dyn DownloadedLenChangeNotify>>,
+    limiter: Arc<GlobalSpeedLimiter>,
+    turn: Arc<sync::Mutex<()>>,
+}
+
+impl DownloadedLenChangeNotify for GlobalSpeedLimiterNotify {
+    fn receive_len(&self, len: usize) -> Option<BoxFuture<'_, ()>> {
+        let inner = self.inner.as_ref().and_then(|n| n.receive_len(len));
+        if self.limiter.get().is_none() {
+            return inner;
+        }
+        Some(async move {
+            if let Some(inner) = inner {
+                inner.await;
+            }
+            self.limiter.acquire(len, &self.turn).await;
+        }.boxed())
+    }
+}
+
+/// Splits the unfinished chunks of a resumed download in halves, the largest first, until
+/// there is one for each connection. A download resumed to rebalance its connections
+/// only splits what is left of the range in `split_range`, at least once, so the other
+/// connections resume their chunks as they were. Has to be layered inside
+/// `DownloadBreakpointResumeExtension`, which loads the resume data.
+pub struct ChunkSplitExtension {
+    pub connection_count: sync::watch::Receiver<NonZeroU8>,
+    /// The first and the last byte of the range, taken once by the next start.
+    pub split_range: Mutex<sync::watch::Receiver<Option<(u64, u64)>>>,
+}
+
+impl<DC: DownloadController> DownloadExtension<DC> for ChunkSplitExtension {
+    type DownloadController = ChunkSplitController<DC>;
+    type ExtensionState = ();
+
+    fn layer(
+        self,
+        _downloader: Arc<HttpFileDownloader>,
+        inner: Arc<DC>,
+    ) -> (Arc<Self::DownloadController>, Self::ExtensionState) {
+        (
+            Arc::new(ChunkSplitController {
+                inner,
+                connection_count: self.connection_count,
+                split_range: self.split_range,
+            }),
+            (),
+        )
+    }
+}
+
+pub struct ChunkSplitController<DC: DownloadController> {
+    inner: Arc<DC>,
+    connection_count: sync::watch::Receiver<NonZeroU8>,
+    split_range: Mutex<sync::watch::Receiver<Option<(u64, u64)>>>,
+}
+
+#[async_trait]
+impl<DC: DownloadController> DownloadController for ChunkSplitController<DC> {
+    async fn download(
+        self: Arc<Self>,
+        mut params: DownloadParams,
+    ) -> Result<BoxFuture<'static, Result<DownloadingEndCause, DownloadError>>, DownloadStartError> {
+        let split_range = {
+            let mut split_range = self.split_range.lock().unwrap();
+            match split_range.has_changed() {
+                Ok(true) => *split_range.borrow_and_update(),
+                _ => None,
+            }
+        };
+        if let Some(chunk_data) = params.archive_data.as_mut().and_then(|n| n.chunk_data.as_mut()) {
+            split_unfinished_chunks(chunk_data, self.connection_count.borrow().get().into(), split_range);
+        }
+        self.inner.to_owned().download(params).await
+    }
+
+    async fn cancel(&self) -> Result<(), DownloadStopError> {
+        self.inner.cancel().await
+    }
+}
+
+fn split_unfinished_chunks(data: &mut ChunkData, connection_count: usize, split_range: Option<(u64, u64)>) {
+    // the connections get their chunks from the ranges nobody started on first
+    if !data.remaining.ranges.is_empty() {
+        return;
+    }
+    // a resumed chunk is what its connection had left, so it ends where the range ended
+    let can_split = |range: &ChunkRange| split_range.is_none_or(|(start, end)| range.start >= start && range.end <= end);
+    let mut is_split = split_range.is_none();
+    while !is_split || data.last_incomplete_chunks.len() < connection_count {
+        let largest = data.last_incomplete_chunks.iter_mut()
+            .filter(|n| can_split(&n.range))
+            .max_by_key(|n| n.range.len());
+        let Some(largest) = largest else {
+            return;
+        };
+        let range = largest.range;
+        if range.len() < 2 * MIN_CHUNK_SIZE as u64 {
+            return;
+        }
+        let half_len = range.len() / 2;
+        largest.range = ChunkRange::from_len(range.start, half_len);
+        // chunk indexes have to be unique, the library counts up from `iter_count`
+        data.iter_count += 1;
+        let index = data.iter_count;
+        data.last_incomplete_chunks.push(ChunkInfo {
+            index,
+            range: ChunkRange::new(range.start + half_len, range.end),
+        });
+        is_split = true;
+    }
+}
+
+/// Keeps track of which ranges the chunks received in full. The library does not look
+/// at the status of a chunk response and saves whatever body it gets, the error page of
+/// a 429 or 503 included, as the chunk. Shared by [`ChunkCheckExtension`], which sees the
+/// chunks, and [`CheckedArchiver`], which only saves the ranges it vouches for as done.
+pub struct ChunkCheck {
+    data: Mutex<ChunkCheckData>,
+    bad_ranges: sync::watch::Sender<Vec<Range<u64>>>,
+}
+
+#[derive(Default)]
+struct ChunkCheckData {
+    total_len: u64,
+    /// The chunks that received data and not yet their whole range.
+    chunks: Vec<SeenChunk>,
+    /// What was resumed and what chunks received in full, sorted and merged.
+    done: Vec<Range<u64>>,
+    max_index: usize,
+    is_overlong: bool,
+    wrong_len: u64,
+}
+
+/// A chunk [`ChunkCheck`] saw receiving. It is not kept alive, a cancelled download waits
+/// until nothing holds its chunks anymore.
+struct SeenChunk {
+    range: ChunkRange,
+    chunk: Weak<ChunkItem>,
+    /// As of the last time it received something, for once it is gone.
+    downloaded_len: u64,
+}
+
+impl SeenChunk {
+    fn received(&self) -> Range<u64> {
+        let downloaded_len = self.chunk.upgrade().map_or(self.downloaded_len, |n| n.downloaded_len.load(Ordering::Relaxed));
+        self.range.start..self.range.start + downloaded_len
+    }
+}
+
+impl Default for ChunkCheck {
+    fn default() -> Self {
+        Self {
+            data: Default::default(),
+            bad_ranges: sync::watch::channel(Vec::new()).0,
+        }
+    }
+}
+
+impl ChunkCheck {
+    /// The ranges where the file holds something else than the server's file, since the
+    /// download last started. The resume data keeps them to download again.
+    pub fn bad_ranges(&self) -> sync::watch::Receiver<Vec<Range<u64>>> {
+        self.bad_ranges.subscribe()
+    }
+
+    /// How much of what the running download received is in the bad ranges.
+    pub fn wrong_len(&self) -> u64 {
+        self.data.lock().unwrap().wrong_len
+    }
+
+    fn start(&self, archive_data: Option<&DownloadArchiveData>) {
+        let mut data = self.data.lock().unwrap();
+        *data = Default::default();
+        if let Some(DownloadArchiveData { downloaded_len, chunk_data: Some(chunk_data) }) = archive_data {
+            data.total_len = downloaded_len + chunk_data.remaining_len();
+            data.done = subtract(iter::once(0..data.total_len), &pending_ranges(chunk_data));
+            data.max_index = chunk_data.iter_count;
+        }
+        self.bad_ranges.send_replace(Vec::new());
+    }
+
+    /// Takes note of the running `chunks`, and whether one of them received more than its
+    /// range.
+    fn add_chunks(&self, total_len: u64, chunks: Vec<Arc<ChunkItem>>) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let data = &mut *data;
+        data.total_len = total_len;
+        for chunk in chunks {
+            let range = chunk.chunk_info.range;
+            let update = chunk.downloaded_len.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n > range.len()).then_some(range.len()));
+            let downloaded_len = match update {
+                Ok(downloaded_len) => {
+                    // the chunk writes all of it over the following ranges once cancelled,
+                    // and the library takes what is left of it to start at `downloaded_len`
+                    self.add_bad_range(range.start..(range.start + downloaded_len).min(total_len));
+                    data.wrong_len += downloaded_len;
+                    data.is_overlong = true;
+                    range.len()
+                }
+                Err(downloaded_len) => downloaded_len,
+            };
+            match data.chunks.iter_mut().find(|n| n.chunk.as_ptr() == Arc::as_ptr(&chunk)) {
+                Some(seen) => seen.downloaded_len = downloaded_len,
+                None => {
+                    data.max_index = data.max_index.max(chunk.chunk_info.index);
+                    data.chunks.push(SeenChunk { range, chunk: Arc::downgrade(&chunk), downloaded_len });
+                }
+            }
+        }
+        data.is_overlong
+    }
+
+    fn add_bad_range(&self, range: Range<u64>) {
+        self.bad_ranges.send_if_modified(|bad_ranges| {
+            if subtract(iter::once(range.clone()), bad_ranges).is_empty() {
+                return false;
+            }
+            insert(bad_ranges, range);
+            true
+        });
+    }
+
+    /// Adds the ranges to `chunk_data` that are left out as done without a chunk having
+    /// received them, and the bad ranges.
+    fn check(&self, chunk_data: &mut ChunkData) {
+        let mut data = self.data.lock().unwrap();
+        let data = &mut *data;
+        // without a length the download has no chunks to check
+        if data.total_len == 0 {
+            return;
+        }
+        data.chunks.retain(|chunk| {
+            if chunk.received().end <= chunk.range.end {
+                return true;
+            }
+            insert(&mut data.done, chunk.range.start..chunk.range.end + 1);
+            false
+        });
+        let pending = pending_ranges(chunk_data);
+        let is_running = |chunk: &SeenChunk| subtract(iter::once(chunk.received()), &pending).is_empty();
+        // a cancelled chunk leaves the rest of its range to download and keeps what it
+        // received, unless that may be an error page it was cut off in
+        let is_cancelled = |chunk: &SeenChunk| subtract(iter::once(chunk.received().end..chunk.range.end + 1), &pending).is_empty();
+        let is_bad = !self.bad_ranges.borrow().is_empty() || data.chunks.iter().any(|n| !is_running(n) && !is_cancelled(n));
+        let mut done = data.done.clone();
+        data.chunks.retain(|chunk| {
+            if is_running(chunk) {
+                return true;
+            }
+            if is_cancelled(chunk) && !is_bad {
+                insert(&mut done, chunk.received());
+                return true;
+            }
+            data.wrong_len += chunk.received().end - chunk.range.start;
+            false
+        });
+        // e.g. a chunk that ended early, or got an empty body and never showed up
+        for range in subtract(subtract(iter::once(0..data.total_len), &pending), &done) {
+            self.add_bad_range(range);
+        }
+        for range in subtract(self.bad_ranges.borrow().clone(), &pending) {
+            // a chunk of its own would get a range as long as the error page in it, and
+            // could get the same error page again without it showing
+            let next = chunk_data.last_incomplete_chunks.iter_mut().find(|n| n.range.start == range.end);
+            if let Some(next) = next {
+                next.range.start = range.start;
+                continue;
+            }
+            // chunk indexes have to be unique, the library counts up from `iter_count`
+            chunk_data.iter_count = chunk_data.iter_count.max(data.max_index) + 1;
+            chunk_data.last_incomplete_chunks.push(ChunkInfo {
+                index: chunk_data.iter_count,
+                range: ChunkRange::new(range.start, range.end - 1),
+            });
+        }
+    }
+}
+
+/// Makes the download fail once a chunk received more than its range, before it writes
+/// over the following ones, and shows [`ChunkCheck`] the chunks. Has to be layered inside
+/// `DownloadSpeedLimiterExtension`, which replaces the notify.
+pub struct ChunkCheckExtension {
+    pub check: Arc<ChunkCheck>,
+}
+
+impl<DC: DownloadController> DownloadExtension<DC> for ChunkCheckExtension {
+    type DownloadController = ChunkCheckController<DC>;
+    type ExtensionState = ();
+
+    fn layer(
+        self,
+        _downloader: Arc<HttpFileDownloader>,
+        inner: Arc<DC>,
+    ) -> (Arc<Self::DownloadController>, Self::ExtensionState) {
+        (Arc::new(ChunkCheckController { inner, check: self.check }), ())
+    }
+}
+
+pub struct ChunkCheckController<DC: DownloadController> {
+    inner: Arc<DC>,
+    check: Arc<ChunkCheck>,
+}
+
+#[async_trait]
+impl<DC: DownloadController> DownloadController for ChunkCheckController<DC> {
+    async fn download(
+        self: Arc<Self>,
+        mut params: DownloadParams,
+    ) -> Result<BoxFuture<'static, Result<DownloadingEndCause, DownloadError>>, DownloadStartError> {
+        let (sender, receiver) = sync::oneshot::channel();
+        params.download_way_oneshot_vec.push(sender);
+        params.downloaded_len_change_notify = Some(Arc::new(ChunkCheckNotify {
+            inner: params.downloaded_len_change_notify.take(),
+            check: self.check.clone(),
+            download_way: Mutex::new(Some(receiver)),
+            chunk_manager: Default::default(),
+        }));
+        self.inner.to_owned().download(params).await
+    }
+
+    async fn cancel(&self) -> Result<(), DownloadStopError> {
+        self.inner.cancel().await
+    }
+}
+
+struct ChunkCheckNotify {
+    inner: Option<Arc<dyn DownloadedLenChangeNotify>>,
+    check: Arc<ChunkCheck>,
+    download_way: Mutex<Option<sync::oneshot::Receiver<Arc<DownloadWay>>>>,
+    chunk_manager: Mutex<Weak<ChunkManager>>,
+}
+
+impl ChunkCheckNotify {
+    /// The library publishes the download way before it starts the chunks.
+    fn chunk_manager(&self) -> Option<Arc<ChunkManager>> {
+        let mut chunk_manager = self.chunk_manager.lock().unwrap();
+        if let Some(Ok(download_way)) = self.download_way.lock().unwrap().take().map(|mut n| n.try_recv()) {
+            if let DownloadWay::Ranges(n) = download_way.as_ref() {
+                *chunk_manager = Arc::downgrade(n);
+            }
+        }
+        chunk_manager.upgrade()
+    }
+}
+
+impl DownloadedLenChangeNotify for ChunkCheckNotify {
+    fn receive_len(&self, len: usize) -> Option<BoxFuture<'_, ()>> {
+        let inner = self.inner.as_ref().and_then(|n| n.receive_len(len));
+        Some(async move {
+            // the chunk that received `len` is running, so it is among the chunks
+            if let Some(chunk_manager) = self.chunk_manager() {
+                let chunks = chunk_manager.get_chunks().await;
+                if self.check.add_chunks(chunk_manager.chunk_iterator.content_length, chunks) {
+                    // holds up the chunks until the failed download cancels them
+                    std::future::pending::<()>().await
+                }
+            }
+            if let Some(inner) = inner {
+                inner.await;
+            }
+        }.boxed())
+    }
+}
+
+/// Builds the archiver of `inner` as a [`CheckedArchiver`].
+pub struct CheckedArchiverBuilder<T: DownloadDataArchiverBuilder> {
+    pub inner: T,
+    pub check: Arc<ChunkCheck>,
+}
+
+impl<T: DownloadDataArchiverBuilder> DownloadDataArchiverBuilder for CheckedArchiverBuilder<T> {
+    type DownloadDataArchiver = CheckedArchiver<T::DownloadDataArchiver>;
+
+    fn build(self, config: &HttpDownloadConfig) -> Self::DownloadDataArchiver {
+        CheckedArchiver {
+            inner: self.inner.build(config),
+            check: self.check,
+            chunk_size: config.chunk_size,
+        }
+    }
+}
+
+/// Saves the resume data with the ranges [`ChunkCheck`] has no full chunk for still to
+/// download, so a resumed download fetches them again.
+pub struct CheckedArchiver<T: DownloadDataArchiver> {
+    inner: T,
+    check: Arc<ChunkCheck>,
+    chunk_size: NonZeroUsize,
+}
+
+#[async_trait]
+impl<T: DownloadDataArchiver> DownloadDataArchiver for CheckedArchiver<T> {
+    async fn save(&self, mut data: Box<DownloadArchiveData>) -> Result<()> {
+        if let Some(chunk_data) = data.chunk_data.as_mut() {
+            let total_len = data.downloaded_len + chunk_data.remaining_len();
+            self.check.check(chunk_data);
+            data.downloaded_len = total_len - chunk_data.remaining_len();
+        }
+        self.inner.save(data).await
+    }
+
+    async fn load(&self) -> Result<Option<DownloadArchiveData>> {
+        let data = self.inner.load().await?;
+        self.check.start(data.as_ref());
+        Ok(data)
+    }
+
+    async fn download_finished(&self) {
+        // the last chunk may have ended early after the last save
+        let mut chunk_data = ChunkData {
+            iter_count: 0,
+            remaining: RemainingChunks { chunk_size: self.chunk_size, ranges: Vec::new() },
+            last_incomplete_chunks: Vec::new(),
+        };
+        self.check.check(&mut chunk_data);
+        match chunk_data.remaining_len() {
+            0 => self.inner.download_finished().await,
+            remaining_len => {
+                let total_len = self.check.data.lock().unwrap().total_len;
+                let _ = self.inner.save(Box::new(DownloadArchiveData {
+                    downloaded_len: total_len - remaining_len,
+                    chunk_data: Some(chunk_data),
+                })).await;
+            }
+        }
+    }
+}
+
+/// The ranges `chunk_data` has left to download, with exclusive ends.
+fn pending_ranges(chunk_data: &ChunkData) -> Vec<Range<u64>> {
+    chunk_data.remaining.ranges.iter()
+        .chain(chunk_data.last_incomplete_chunks.iter().map(|n| &n.range))
+        .map(|n| n.start..n.end + 1)
+        .collect()
+}
+
+/// `ranges` without what is in `other`.
+fn subtract(ranges: impl IntoIterator<Item = Range<u64>>, other: &[Range<u64>]) -> Vec<Range<u64>> {
+    let ranges = ranges.into_iter().filter(|n| !n.is_empty()).collect();
+    other.iter().fold(ranges, |ranges: Vec<_>, cut| {
+        ranges.into_iter()
+            .flat_map(|n| [n.start..n.end.min(cut.start), n.start.max(cut.end)..n.end])
+            .filter(|n| !n.is_empty())
+            .collect()
+    })
+}
+
+/// Adds `range` to the sorted `ranges`, merging the ones it touches.
+fn insert(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    let start = ranges.partition_point(|n| n.end < range.start);
+    let end = ranges.partition_point(|n| n.start <= range.end);
+    let merged = ranges[start..end].iter().fold(range, |a, b| a.start.min(b.start)..a.end.max(b.end));
+    ranges.splice(start..end, [merged]);
+}
+
+#[cfg(test)]
+// the ranges are ranges of bytes, not meant to be collected
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn chunk_data(remaining: &[(u64, u64)], incomplete: &[(u64, u64)]) -> ChunkData {
+        ChunkData {
+            iter_count: incomplete.len(),
+            remaining: RemainingChunks {
+                chunk_size: NonZeroUsize::new(MIN_CHUNK_SIZE).unwrap(),
+                ranges: remaining.iter().map(|(start, end)| ChunkRange::new(*start, *end)).collect(),
+            },
+            last_incomplete_chunks: incomplete.iter().enumerate()
+                .map(|(index, (start, end))| ChunkInfo { index: index + 1, range: ChunkRange::new(*start, *end) })
+                .collect(),
+        }
+    }
+
+    fn incomplete(chunk_data: &ChunkData) -> Vec<(usize, u64, u64)> {
+        chunk_data.last_incomplete_chunks.iter().map(|n| (n.index, n.range.start, n.range.end)).collect()
+    }
+
+    /// A [`ChunkCheck`] that resumed `total_len` bytes with `pending` left to download.
+    fn resumed(total_len: u64, pending: &[(u64, u64)]) -> ChunkCheck {
+        let check = ChunkCheck::default();
+        let chunk_data = chunk_data(&[], pending);
+        check.start(Some(&DownloadArchiveData {
+            downloaded_len: total_len - chunk_data.remaining_len(),
+            chunk_data: Some(chunk_data),
+        }));
+        check
+    }
+
+    #[test]
+    fn subtract_ranges() {
+        assert_eq!(subtract([0..10], &[]), vec![0..10]);
+        assert_eq!(subtract([0..10], &[2..4, 6..8]), vec![0..2, 4..6, 8..10]);
+        assert_eq!(subtract([0..10, 20..30], &[5..25]), vec![0..5, 25..30]);
+        assert_eq!(subtract([0..10], &[0..10]), Vec::<Range<u64>>::new());
+        assert_eq!(subtract([0..0, 5..5], &[]), Vec::<Range<u64>>::new());
+        assert_eq!(subtract([0..0], &[0..10]), Vec::<Range<u64>>::new());
+    }
+
+    #[test]
+    fn insert_ranges() {
+        let mut ranges = Vec::new();
+        insert(&mut ranges, 10..20);
+        insert(&mut ranges, 30..40);
+        assert_eq!(ranges, vec![10..20, 30..40]);
+        insert(&mut ranges, 0..5);
+        assert_eq!(ranges, vec![0..5, 10..20, 30..40]);
+        // touching ranges merge
+        insert(&mut ranges, 20..25);
+        assert_eq!(ranges, vec![0..5, 10..25, 30..40]);
+        insert(&mut ranges, 3..35);
+        assert_eq!(ranges, vec![0..40]);
+        insert(&mut ranges, 50..60);
+        insert(&mut ranges, 10..20);
+        assert_eq!(ranges, vec![0..40, 50..60]);
+    }
+
+    #[test]
+    fn check_without_len() {
+        let check = ChunkCheck::default();
+        let mut data = chunk_data(&[], &[]);
+        check.check(&mut data);
+        assert_eq!(data.remaining_len(), 0);
+        assert!(check.bad_ranges().borrow().is_empty());
+        assert_eq!(check.wrong_len(), 0);
+    }
+
+    #[test]
+    fn check_resumed() {
+        let check = resumed(100, &[(50, 99)]);
+        let mut data = chunk_data(&[], &[(50, 99)]);
+        check.check(&mut data);
+        assert_eq!(incomplete(&data), vec![(1, 50, 99)]);
+        assert!(check.bad_ranges().borrow().is_empty());
+    }
+
+    #[test]
+    fn check_unknown_range_joins_next_chunk() {
+        // bytes 50 to 59 left the pending ranges without a chunk that received them
+        let check = resumed(100, &[(50, 99)]);
+        let mut data = chunk_data(&[], &[(60, 99)]);
+        check.check(&mut data);
+        assert_eq!(*check.bad_ranges().borrow(), vec![50..60]);
+        assert_eq!(incomplete(&data), vec![(1, 50, 99)]);
+    }
+
+    #[test]
+    fn check_unknown_range_gets_own_chunk() {
+        let check = resumed(100, &[(0, 9), (50, 99)]);
+        let mut data = chunk_data(&[], &[(50, 99)]);
+        data.iter_count = 4;
+        check.check(&mut data);
+        assert_eq!(*check.bad_ranges().borrow(), vec![0..10]);
+        assert_eq!(incomplete(&data), vec![(1, 50, 99), (5, 0, 9)]);
+        assert_eq!(data.remaining_len(), 60);
+    }
+
+    const M: u64 = MIN_CHUNK_SIZE as u64;
+
+    #[test]
+    fn split_waits_for_remaining_ranges() {
+        let mut data = chunk_data(&[(8 * M, 16 * M - 1)], &[(0, 8 * M - 1)]);
+        split_unfinished_chunks(&mut data, 4, None);
+        assert_eq!(incomplete(&data), vec![(1, 0, 8 * M - 1)]);
+        split_unfinished_chunks(&mut data, 4, Some((0, 8 * M - 1)));
+        assert_eq!(incomplete(&data), vec![(1, 0, 8 * M - 1)]);
+    }
+
+    #[test]
+    fn split_largest_first() {
+        let mut data = chunk_data(&[], &[(0, 4 * M - 1), (4 * M, 12 * M - 1)]);
+        split_unfinished_chunks(&mut data, 4, None);
+        assert_eq!(incomplete(&data), vec![(1, 0, 4 * M - 1), (2, 4 * M, 8 * M - 1), (3, 8 * M, 10 * M - 1), (4, 10 * M, 12 * M - 1)]);
+        // enough chunks for the connections already
+        split_unfinished_chunks(&mut data, 4, None);
+        assert_eq!(data.last_incomplete_chunks.len(), 4);
+    }
+
+    #[test]
+    fn split_range_only() {
+        // the connection of the first chunk had received 1M of 4M
+        let mut data = chunk_data(&[], &[(M, 4 * M - 1), (4 * M, 12 * M - 1)]);
+        split_unfinished_chunks(&mut data, 3, Some((0, 4 * M - 1)));
+        assert_eq!(incomplete(&data), vec![(1, M, 2 * M + M / 2 - 1), (2, 4 * M, 12 * M - 1), (3, 2 * M + M / 2, 4 * M - 1)]);
+    }
+
+    #[test]
+    fn split_range_at_least_once() {
+        let mut data = chunk_data(&[], &[(0, 4 * M - 1), (4 * M, 12 * M - 1)]);
+        split_unfinished_chunks(&mut data, 2, Some((0, 4 * M - 1)));
+        assert_eq!(incomplete(&data), vec![(1, 0, 2 * M - 1), (2, 4 * M, 12 * M - 1), (3, 2 * M, 4 * M - 1)]);
+    }
+
+    #[test]
+    fn split_range_too_small() {
+        let mut data = chunk_data(&[], &[(M, 2 * M - 1), (4 * M, 12 * M - 1)]);
+        split_unfinished_chunks(&mut data, 4, Some((0, 2 * M - 1)));
+        assert_eq!(incomplete(&data), vec![(1, M, 2 * M - 1), (2, 4 * M, 12 * M - 1)]);
+    }
+
+    #[test]
+    fn check_finished() {
+        let check = resumed(100, &[(50, 99)]);
+        let mut data = chunk_data(&[], &[]);
+        check.check(&mut data);
+        assert_eq!(*check.bad_ranges().borrow(), vec![50..100]);
+        assert_eq!(incomplete(&data), vec![(2, 50, 99)]);
+    }
+}
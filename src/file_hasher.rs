@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use http_downloader::{ChunkManager, DownloadWay};
+use tokio::sync;
+
+use crate::checksum::{HashAlgorithm, Hasher};
+
+const READ_BUF_LEN: usize = 1024 * 1024;
+
+/// Hashes a file while it is being downloaded, so the digests are ready without reading
+/// the whole file again once it finished. Chunks complete out of order, so only the
+/// prefix before the first unfinished chunk is hashed, see [`written_prefix_len`].
+pub struct FileHasher {
+    file_path: PathBuf,
+    algorithms: Vec<HashAlgorithm>,
+    hashers: Mutex<Vec<Hasher>>,
+    hashed_len: AtomicU64,
+}
+
+impl FileHasher {
+    pub fn new(file_path: PathBuf, algorithms: Vec<HashAlgorithm>) -> Self {
+        Self {
+            file_path,
+            hashers: Mutex::new(algorithms.iter().copied().map(Hasher::new).collect()),
+            algorithms,
+            hashed_len: AtomicU64::new(0),
+        }
+    }
+
+    pub fn algorithms(&self) -> &[HashAlgorithm] {
+        &self.algorithms
+    }
+
+    pub fn hashed_len(&self) -> u64 {
+        self.hashed_len.load(Ordering::Relaxed)
+    }
+
+    /// Keeps hashing the written prefix of the file while `download_way` reports a
+    /// download with ranges, never resolves.
+    pub async fn follow(self: Arc<Self>, mut download_way: sync::watch::Receiver<Option<Arc<DownloadWay>>>) {
+        // a chunk is missing from both the remaining ranges and the downloading chunks for a
+        // moment after it was taken, the smaller of two samples is never past that gap
+        let mut previous_len = 0;
+        loop {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let chunk_manager = match download_way.borrow_and_update().as_deref() {
+                Some(DownloadWay::Ranges(chunk_manager)) => chunk_manager.clone(),
+                _ => {
+                    previous_len = 0;
+                    continue;
+                }
+            };
+            let len = written_prefix_len(&chunk_manager).await;
+            let hashed_until = previous_len.min(len);
+            previous_len = len;
+            if hashed_until <= self.hashed_len() {
+                continue;
+            }
+            let hasher = self.clone();
+            let _ = tokio::task::spawn_blocking(move || hasher.hash(Some(hashed_until))).await;
+        }
+    }
+
+    /// Hashes the rest of the file and returns the digests in the order of [`Self::algorithms`].
+    pub fn finish(&self) -> io::Result<Vec<String>> {
+        self.hash(None)?;
+        let hashers = std::mem::take(&mut *self.hashers.lock().unwrap());
+        Ok(hashers.into_iter().map(Hasher::finalize).collect())
+    }
+
+    /// Hashes from where it stopped up to `until`, or to the end of the file.
+    fn hash(&self, until: Option<u64>) -> io::Result<()> {
+        let mut hashers = self.hashers.lock().unwrap();
+        let mut file = File::open(&self.file_path)?;
+        let mut hashed_len = self.hashed_len();
+        file.seek(SeekFrom::Start(hashed_len))?;
+        let mut buf = vec![0; READ_BUF_LEN];
+        loop {
+            let max_len = match until {
+                Some(until) => (until.saturating_sub(hashed_len) as usize).min(buf.len()),
+                None => buf.len(),
+            };
+            if max_len == 0 {
+                break;
+            }
+            let len = file.read(&mut buf[..max_len])?;
+            if len == 0 {
+                break;
+            }
+            for hasher in hashers.iter_mut() {
+                hasher.update(&buf[..len]);
+            }
+            hashed_len += len as u64;
+            self.hashed_len.store(hashed_len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Length of the file prefix that is completely written: a chunk is only written to the
+/// file once it finished, so this is the start of the first chunk that is still queued
+/// or downloading.
+async fn written_prefix_len(chunk_manager: &ChunkManager) -> u64 {
+    let len = {
+        let data = chunk_manager.chunk_iterator.data.lock();
+        data.remaining.ranges.iter()
+            .chain(data.last_incomplete_chunks.iter().map(|n| &n.range))
+            .map(|n| n.start)
+            .min()
+            .unwrap_or(chunk_manager.chunk_iterator.content_length)
+    };
+    chunk_manager.get_chunks().await.iter()
+        .map(|n| n.chunk_info.range.start)
+        .fold(len, u64::min)
+}
@@ -0,0 +1,39 @@
+// a cell filled to 0/8 up to 8/8 of its height
+const LEVELS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Drawn across a [`chart`] at the speed limit.
+pub const LIMIT_LINE: char = '─';
+
+/// The last `width` samples as one line of blocks scaled to the highest of them, right
+/// aligned. A zero stays empty, so a stall shows as a gap.
+pub fn sparkline(samples: &[u64], width: usize) -> String {
+    let samples = &samples[samples.len().saturating_sub(width)..];
+    let max = samples.iter().copied().max().unwrap_or(0).max(1);
+    let mut line = " ".repeat(width - samples.len());
+    line.extend(samples.iter().map(|n| match n {
+        0 => LEVELS[0],
+        n => LEVELS[(n * 8).div_ceil(max).clamp(1, 8) as usize],
+    }));
+    line
+}
+
+/// The last `width` samples as `height` lines of columns from 0 at the bottom to `max`
+/// at the top, right aligned, with a [`LIMIT_LINE`] at `limit` where the columns stay
+/// below it.
+pub fn chart(samples: &[u64], width: usize, height: usize, max: u64, limit: Option<u64>) -> Vec<String> {
+    let samples = &samples[samples.len().saturating_sub(width)..];
+    let eighths = |n: u64| (u128::from(n) * height as u128 * 8 / u128::from(max.max(1))) as usize;
+    // the row the limit is in, counted from the bottom
+    let limit_row = limit.filter(|n| *n <= max).map(|n| eighths(n).saturating_sub(1) / 8);
+    (0..height)
+        .rev()
+        .map(|row| {
+            let padding = std::iter::repeat_n(0, width - samples.len());
+            padding.chain(samples.iter().copied())
+                .map(|n| {
+                    let level = eighths(n).saturating_sub(row * 8).min(8);
+                    if level == 0 && limit_row == Some(row) { LIMIT_LINE } else { LEVELS[level] }
+                })
+                .collect()
+        })
+        .collect()
+}
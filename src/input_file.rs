@@ -0,0 +1,99 @@
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use url::Url;
+
+use crate::job::{Job, parse_header};
+
+/// A line of the input file that could not be used.
+#[derive(Debug)]
+pub struct InputFileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for InputFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Reads jobs from `path`, or from stdin when `path` is `-`.
+pub fn read(path: &Path) -> Result<(Vec<Job>, Vec<InputFileError>)> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|err| anyhow::Error::msg(format!("{}: {err}", path.display())))?
+    };
+    Ok(parse(&content))
+}
+
+/// Parses the aria2 style input format: one URL per line, followed by indented
+/// `name=value` options for that URL. Blank lines and `#` comments are ignored. Like
+/// with -H, the last `header` of a name wins.
+///
+/// ```text
+/// https://example.com/file.iso
+///   out=debian.iso
+///   checksum=sha256=<hex>
+///   header=Authorization: Bearer token
+/// ```
+pub fn parse(content: &str) -> (Vec<Job>, Vec<InputFileError>) {
+    let mut jobs = Vec::new();
+    let mut errors = Vec::new();
+    let mut current: Option<Job> = None;
+    let mut is_in_entry = false;
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if !is_in_entry {
+                errors.push(InputFileError { line: line_number, message: format!("option `{trimmed}` does not follow a URL") });
+                continue;
+            }
+            // options of an entry whose URL was invalid are skipped with it
+            let Some(job) = current.as_mut() else {
+                continue;
+            };
+            if let Err(err) = apply_option(job, trimmed) {
+                errors.push(InputFileError { line: line_number, message: err.to_string() });
+            }
+            continue;
+        }
+
+        jobs.extend(current.take());
+        is_in_entry = true;
+        match Url::parse(trimmed) {
+            Ok(url) => current = Some(Job::new(url)),
+            Err(err) => errors.push(InputFileError { line: line_number, message: format!("invalid URL `{trimmed}`: {err}") }),
+        }
+    }
+    jobs.extend(current);
+    (jobs, errors)
+}
+
+fn apply_option(job: &mut Job, option: &str) -> Result<()> {
+    let (name, value) = option
+        .split_once('=')
+        .ok_or_else(|| anyhow::Error::msg(format!("invalid option `{option}`, expected `name=value`")))?;
+    let value = value.trim();
+    match name.trim() {
+        "out" => job.output = Some(PathBuf::from(value)),
+        "dir" => job.dir = Some(PathBuf::from(value)),
+        "header" => {
+            let (name, value) = parse_header(value)?;
+            job.header_map.insert(name, value);
+        }
+        "checksum" => job.checksums.push(value.parse().map_err(anyhow::Error::msg)?),
+        name => anyhow::bail!("unknown option `{name}`"),
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use url::Url;
+
+use crate::auth;
+use crate::checksum::Checksum;
+
+/// One URL to download together with the options that only apply to it.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub url: Url,
+    pub output: Option<PathBuf>,
+    pub dir: Option<PathBuf>,
+    pub header_map: HeaderMap,
+    pub checksums: Vec<Checksum>,
+}
+
+impl Job {
+    pub fn new(mut url: Url) -> Self {
+        let mut header_map = HeaderMap::new();
+        if let Some(authorization) = auth::take_url_credentials(&mut url) {
+            header_map.insert(header::AUTHORIZATION, authorization);
+        }
+        Self {
+            url,
+            output: None,
+            dir: None,
+            header_map,
+            checksums: Vec::new(),
+        }
+    }
+
+    /// Adds the headers of `header_map` this job does not set itself, which has one value
+    /// per name like the requests of the library.
+    pub fn add_default_headers(&mut self, header_map: &HeaderMap) {
+        for (name, value) in header_map.iter() {
+            if !self.header_map.contains_key(name) {
+                self.header_map.insert(name, value.clone());
+            }
+        }
+    }
+}
+
+/// Parses a `Name: value` header line.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| anyhow::Error::msg(format!("invalid header `{header}`, expected `Name: value`")))?;
+    Ok((
+        HeaderName::from_bytes(name.trim().as_bytes())?,
+        HeaderValue::from_str(value.trim())?,
+    ))
+}
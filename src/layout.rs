@@ -0,0 +1,100 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// when the terminal size cannot be read, e.g. without a terminal
+const DEFAULT_WIDTH: usize = 80;
+
+/// Columns of the terminal, read again for every frame so resizing takes effect.
+pub fn terminal_width() -> usize {
+    crossterm::terminal::size().ok()
+        .map(|(cols, _rows)| usize::from(cols))
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// Number of terminal cells `text` takes, CJK characters take two.
+pub fn width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+/// Cuts `text` to at most `width` cells, ending with `…` if anything was cut.
+pub fn truncate(text: &str, width: usize) -> String {
+    if self::width(text) <= width {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut truncated_width = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        // room for the `…`
+        if truncated_width + char_width + 1 > width {
+            break;
+        }
+        truncated.push(c);
+        truncated_width += char_width;
+    }
+    if width > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// Pads or truncates `text` to exactly `width` cells.
+pub fn fit(text: &str, width: usize) -> String {
+    let mut text = truncate(text, width);
+    // a cut before a wide character leaves a cell free
+    let padding = width.saturating_sub(self::width(&text));
+    text.extend(std::iter::repeat_n(' ', padding));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXTS: [&str; 5] = ["", "a", "hello.bin", "日本語のファイル.zip", "a日b本c"];
+
+    #[test]
+    fn truncate_cases() {
+        let cases = [
+            ("hello", 0, ""),
+            ("hello", 1, "…"),
+            ("hello", 2, "h…"),
+            ("hello", 4, "hel…"),
+            ("hello", 5, "hello"),
+            ("hello", 6, "hello"),
+            ("日本語", 1, "…"),
+            ("日本語", 2, "…"),
+            ("日本語", 3, "日…"),
+            ("日本語", 4, "日…"),
+            ("日本語", 5, "日本…"),
+            ("日本語", 6, "日本語"),
+        ];
+        for (text, width, expected) in cases {
+            assert_eq!(truncate(text, width), expected, "{text:?} in {width}");
+        }
+    }
+
+    #[test]
+    fn fit_cases() {
+        let cases = [
+            ("hello", 0, ""),
+            ("hello", 1, "…"),
+            ("hello", 7, "hello  "),
+            ("日本語", 4, "日… "),
+            ("日本語", 6, "日本語"),
+        ];
+        for (text, width, expected) in cases {
+            assert_eq!(fit(text, width), expected, "{text:?} in {width}");
+        }
+    }
+
+    #[test]
+    fn any_width() {
+        for text in TEXTS {
+            for width in 0..=30 {
+                assert!(self::width(&truncate(text, width)) <= width, "{text:?} in {width}");
+                assert_eq!(self::width(&fit(text, width)), width, "{text:?} in {width}");
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync;
+
+use crate::progress::ProgressBar;
+
+// `-` never throttles below this
+const MIN_SPEED_LIMIT: usize = 16 * 1024;
+
+/// The speed limit of each download, changed while downloading from the TUI or the
+/// control socket, or by a --limit-schedule. `None` is unlimited.
+#[derive(Clone)]
+pub struct SpeedLimit {
+    sender: Arc<sync::watch::Sender<Option<usize>>>,
+    /// The schedule rule that set the limit, until it is changed by hand.
+    rule: Arc<Mutex<Option<String>>>,
+}
+
+impl SpeedLimit {
+    pub fn new(byte_count_per: Option<usize>) -> Self {
+        Self {
+            sender: Arc::new(sync::watch::channel(byte_count_per.filter(|n| *n > 0)).0),
+            rule: Default::default(),
+        }
+    }
+
+    pub fn get(&self) -> Option<usize> {
+        *self.sender.borrow()
+    }
+
+    /// `None` or `Some(0)` removes the limit.
+    pub fn set(&self, byte_count_per: Option<usize>) {
+        self.apply_schedule(byte_count_per, None);
+    }
+
+    pub fn rule(&self) -> Option<String> {
+        self.rule.lock().unwrap().clone()
+    }
+
+    /// Sets the limit of the active schedule `rule`, `None` outside of all rules.
+    pub fn apply_schedule(&self, byte_count_per: Option<usize>, rule: Option<String>) {
+        *self.rule.lock().unwrap() = rule;
+        self.sender.send_replace(byte_count_per.filter(|n| *n > 0));
+    }
+
+    pub fn subscribe(&self) -> sync::watch::Receiver<Option<usize>> {
+        self.sender.subscribe()
+    }
+
+    /// Doubles the limit, an unlimited download stays unlimited.
+    pub fn increase(&self) {
+        *self.rule.lock().unwrap() = None;
+        self.sender.send_modify(|limit| {
+            *limit = limit.map(|n| n.saturating_mul(2));
+        });
+    }
+
+    /// Halves the limit, or starts at half of `current_speed` when there is none.
+    pub fn decrease(&self, current_speed: u64) {
+        *self.rule.lock().unwrap() = None;
+        self.sender.send_modify(|limit| {
+            let byte_count_per = limit.unwrap_or(current_speed as usize);
+            *limit = Some((byte_count_per / 2).max(MIN_SPEED_LIMIT));
+        });
+    }
+}
+
+/// `2.00 MiB/s`, or `off` without a limit.
+pub fn format_byte_count_per(byte_count_per: Option<usize>) -> String {
+    match byte_count_per {
+        Some(byte_count_per) => {
+            let (size, unit) = ProgressBar::byte_unit(byte_count_per as u64);
+            format!("{size:.2} {unit}/s")
+        }
+        None => "off".to_string(),
+    }
+}
+
+/// The limit followed by the schedule rule that set it.
+pub fn format_speed_limit(speed_limit: &SpeedLimit) -> String {
+    let mut text = format_byte_count_per(speed_limit.get());
+    if let Some(rule) = speed_limit.rule() {
+        text.push_str(&format!(" ({rule})"));
+    }
+    text
+}
+
+/// Token bucket for --global-speed-limit that every download draws from after its own
+/// limit. A download waits for at most one read at a time, so downloads take turns and
+/// share the bandwidth equally whatever their connection count.
+pub struct GlobalSpeedLimiter {
+    byte_count_per: AtomicUsize,
+    /// When the bytes reserved so far have been paid for.
+    next_free: Mutex<Instant>,
+}
+
+// how far an idle limiter lets a download run ahead
+const GLOBAL_BURST: Duration = Duration::from_millis(100);
+// reads are reserved in parts of this size so the turns are about the same number of bytes
+const GLOBAL_QUANTUM: usize = 16 * 1024;
+
+impl GlobalSpeedLimiter {
+    pub fn new(byte_count_per: Option<usize>) -> Self {
+        Self {
+            byte_count_per: AtomicUsize::new(byte_count_per.unwrap_or(0)),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn get(&self) -> Option<usize> {
+        Some(self.byte_count_per.load(Ordering::Relaxed)).filter(|n| *n > 0)
+    }
+
+    /// `None` or `Some(0)` removes the limit.
+    pub fn set(&self, byte_count_per: Option<usize>) {
+        *self.next_free.lock().unwrap() = Instant::now();
+        self.byte_count_per.store(byte_count_per.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Waits until `len` received bytes fit into the limit, `turn` is the download's own.
+    pub async fn acquire(&self, len: usize, turn: &sync::Mutex<()>) {
+        let _turn = turn.lock().await;
+        let mut remaining = len;
+        while remaining > 0 {
+            let Some(byte_count_per) = self.get() else {
+                return;
+            };
+            let part = remaining.min(GLOBAL_QUANTUM);
+            remaining -= part;
+            let wake_at = {
+                let mut next_free = self.next_free.lock().unwrap();
+                let now = Instant::now();
+                let start = (*next_free).max(now.checked_sub(GLOBAL_BURST).unwrap_or(now));
+                *next_free = start + Duration::from_secs_f64(part as f64 / byte_count_per as f64);
+                *next_free
+            };
+            tokio::time::sleep_until(wake_at.into()).await;
+        }
+    }
+}
@@ -1,178 +1,465 @@
-use std::fmt::Write;
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::io::stdout;
 use std::num::{NonZeroU8, NonZeroUsize};
-use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use clap::{arg, Parser};
-use crossterm::cursor::{Hide, MoveToColumn, MoveToNextLine, MoveToPreviousLine};
+use clap::{CommandFactory, Parser};
+use crossterm::cursor::{Hide, Show};
 use crossterm::execute;
-use crossterm::style::Print;
-use crossterm::terminal::{Clear, ClearType};
-use http_downloader::{breakpoint_resume::DownloadBreakpointResumeExtension, HttpDownloaderBuilder, speed_limiter::DownloadSpeedLimiterExtension, speed_tracker::DownloadSpeedTrackerExtension};
-use http_downloader::bson_file_archiver::{ArchiveFilePath, BsonFileArchiverBuilder};
+use http_downloader::UrlFileName;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tokio::sync;
 use url::Url;
 
+use crate::checksum::{Checksum, ChecksumMismatchAction, HashAlgorithm};
+use crate::control::{ControlSocket, Controls};
+use crate::download::Download;
+use crate::job::{Job, parse_header};
+use crate::limit::{GlobalSpeedLimiter, SpeedLimit};
+use crate::progress::ProgressView;
+use crate::schedule::{LimitSchedule, LocalClock};
+use crate::tui::Tui;
+
+mod auth;
+mod auto;
+mod checksum;
+mod config;
+mod connection;
+mod control;
+mod download;
+mod extension;
+mod file_hasher;
+mod graph;
+mod input_file;
+mod job;
+mod layout;
+mod limit;
+mod progress;
+mod proxy;
+mod retry;
+mod schedule;
+mod scheduler;
+mod segment;
+mod signal;
+mod size;
+mod tui;
+
+// how long a second signal still waits for the downloads to stop
+const FORCED_EXIT_WAIT: Duration = Duration::from_secs(1);
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 struct Args {
-    url: Url,
+    #[arg(required_unless_present = "input_file")]
+    urls: Vec<Url>,
+
+    /// Read URLs from a file, one per line (`-` for stdin)
+    #[arg(short, long)]
+    input_file: Option<PathBuf>,
+
+    /// Save the download to this file name (relative to --dir)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Directory to save into [default: $XDG_DOWNLOAD_DIR or the current directory]
+    #[arg(short = 'd', long)]
+    dir: Option<PathBuf>,
 
     #[arg(short, long, default_value_t = NonZeroU8::new(3).unwrap())]
     connection_count: NonZeroU8,
 
-    #[arg(long, default_value_t = NonZeroUsize::new(1024 * 1024 * 4).unwrap())]
+    /// Start with one connection and add more while the download gets faster, with chunks
+    /// sized to the speed (--chunk-size is the initial size)
+    #[arg(long, conflicts_with = "connection_count")]
+    auto: bool,
+
+    /// Size of the ranges each connection requests, e.g. `512K` or `4MiB`
+    #[arg(long, value_parser = size::parse_non_zero, default_value = "4MiB")]
     chunk_size: NonZeroUsize,
 
-    #[arg(short, long, default_value = None)]
+    /// Bytes per second, e.g. `512K`, `1.5M` or `10Mbit`
+    #[arg(short, long, value_parser = size::parse, default_value = None)]
     speed_limit: Option<usize>,
 
+    /// Bytes per second shared by all downloads, which take turns
+    #[arg(long, value_parser = size::parse)]
+    global_speed_limit: Option<usize>,
+
+    /// Speed limits by local time of day, e.g. `08:00-18:00=2M,18:00-08:00=0` (0 is
+    /// unlimited), --speed-limit applies outside of all ranges
+    #[arg(long, value_name = "SCHEDULE")]
+    limit_schedule: Option<LimitSchedule>,
+
+    /// Send an extra request header, e.g. `-H "Authorization: Bearer <token>"`, repeatable,
+    /// the last value of a repeated name wins
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    #[arg(long)]
+    user_agent: Option<HeaderValue>,
+
+    #[arg(long)]
+    referer: Option<HeaderValue>,
+
+    /// Cookies to send, e.g. `k=v; k2=v2`
+    #[arg(long)]
+    cookie: Option<HeaderValue>,
+
+    /// Basic auth credentials as `user:password`
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Send `Authorization: Bearer <TOKEN>`
+    #[arg(long, conflicts_with = "user")]
+    bearer_token: Option<String>,
+
+    /// Read the bearer token from a file
+    #[arg(long, conflicts_with_all = ["user", "bearer_token"])]
+    bearer_token_file: Option<PathBuf>,
+
+    /// Look up credentials by host in this file [default: $NETRC or ~/.netrc]
+    #[arg(long)]
+    netrc_file: Option<PathBuf>,
+
+    /// Connect through this proxy (http://, https:// or socks5h://, credentials as
+    /// `user:password@`) [default: $HTTP_PROXY, $HTTPS_PROXY or $ALL_PROXY]
+    #[arg(long, value_name = "URL")]
+    proxy: Option<Url>,
+
+    /// Ignore --proxy and the proxy environment variables
+    #[arg(long)]
+    no_proxy: bool,
+
+    /// How often to retry a download after a transient error
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Wait before the first retry, doubled for each further retry (e.g. `500ms`, `2s`, `1m`)
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    retry_wait: Duration,
+
+    /// Longest wait between retries, unless the server asks for more with Retry-After
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    retry_max_wait: Duration,
+
+    /// HTTP status codes worth retrying
+    #[arg(long, value_delimiter = ',', default_value = "429,500,502,503,504")]
+    retry_on: Vec<u16>,
+
+    /// Give up connecting to a server after this long (e.g. `10s`)
+    #[arg(long, value_parser = parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// Reconnect when a connection received nothing for this long
+    #[arg(long, value_parser = parse_duration)]
+    read_timeout: Option<Duration>,
+
+    /// Reconnect a connection that stays slower than this, e.g. `50K`, unless a speed
+    /// limit is set
+    #[arg(long, value_parser = size::parse)]
+    lowest_speed_limit: Option<usize>,
+
+    /// Stop after this long, keeping the resume data, and exit with code 124
+    #[arg(long, value_parser = parse_duration)]
+    max_time: Option<Duration>,
+
+    /// Verify the finished file, e.g. `sha256=<hex>` (sha256, sha1, md5 or blake3), repeatable
+    #[arg(long)]
+    checksum: Vec<Checksum>,
+
+    /// What to do with a file that fails --checksum verification
+    #[arg(long, value_enum, default_value_t = ChecksumMismatchAction::Keep)]
+    on_checksum_mismatch: ChecksumMismatchAction,
+
+    /// Print the digest of each downloaded file (sha256, sha1, md5 or blake3), hashed while
+    /// downloading, repeatable
+    #[arg(long, value_name = "ALGORITHM")]
+    print_hash: Vec<HashAlgorithm>,
+
+    /// Number of downloads running at the same time
+    #[arg(short = 'j', long, default_value_t = NonZeroUsize::new(3).unwrap())]
+    max_concurrent: NonZeroUsize,
+
     #[arg(short, long, default_value_t = true)]
     progress: bool,
 
     #[arg(long, default_value_t = false)]
     silence: bool,
+
+    /// Full screen interface with a download list
+    #[arg(long, conflicts_with = "silence")]
+    tui: bool,
+
+    /// Accept commands like `limit 2M` or `connections 8` on this Unix socket while downloading
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Read default options from this file, one `long-option-name = value` per line
+    /// [default: $XDG_CONFIG_HOME/hd/config]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let result = std::env::current_exe()?;
-
-    let save_dir = result.join("../");
-    let (downloader, (speed_state, ..)) =
-        HttpDownloaderBuilder::new(args.url, save_dir)
-            .download_connection_count(args.connection_count)
-            .chunk_size(args.chunk_size)
-            .build((
-                DownloadSpeedTrackerExtension { log: false },
-                DownloadSpeedLimiterExtension {
-                    byte_count_per: args.speed_limit
-                },
-                DownloadBreakpointResumeExtension {
-                    download_archiver_builder: BsonFileArchiverBuilder::new(ArchiveFilePath::Suffix("bson".to_string()))
-                }
-            ));
-    let file_path = downloader.get_file_path();
-    execute!(
-        stdout(),
-        Hide
-    )?;
-    let mut is_first = true;
-    let finished_future = downloader.start().await?;
-    if !args.silence && args.progress {
-        let mut bar = ProgressBar::new(62);
-        tokio::spawn({
-            let mut downloaded_len_receiver = downloader.downloaded_len_receiver().clone();
-            async move {
-                let total_len = downloader.total_size().await;
-                while downloaded_len_receiver.changed().await.is_ok() {
-                    let downloaded_len = *downloaded_len_receiver.borrow();
-                    if let Some(total_len) = total_len {
-                        let buf = bar.update(downloaded_len, total_len, speed_state.download_speed())?;
-                        if is_first {
-                            execute!(
-                                stdout(),
-                                Print(buf)
-                            )?;
-                            is_first = false;
-                        } else {
-                            execute!(
-                                stdout(),
-                                Clear(ClearType::CurrentLine),
-                                MoveToPreviousLine(1),
-                                Clear(ClearType::CurrentLine),
-                                MoveToColumn(0),
-                                Print(buf)
-                            )?;
-                        }
-                    }
+    let args = parse_args()?;
+    let mut jobs = args.urls.iter().cloned().map(Job::new).collect::<Vec<_>>();
+    let mut invalid_line_count = 0;
+    if let Some(input_file) = &args.input_file {
+        let (input_jobs, errors) = input_file::read(input_file)?;
+        for err in errors.iter() {
+            eprintln!("{}: {}", input_file.display(), err);
+        }
+        invalid_line_count = errors.len();
+        jobs.extend(input_jobs);
+    }
+    if jobs.is_empty() {
+        anyhow::bail!("no URLs to download");
+    }
+    if args.output.is_some() && jobs.len() > 1 {
+        anyhow::bail!("--output can only be used with a single URL");
+    }
+    if !args.checksum.is_empty() {
+        if jobs.len() > 1 {
+            anyhow::bail!("--checksum can only be used with a single URL");
+        }
+        jobs[0].checksums.extend(args.checksum.iter().cloned());
+    }
 
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                }
-                Result::<()>::Ok(())
+    let header_map = request_headers(&args)?;
+    let netrc = auth::Netrc::read(args.netrc_file.as_deref())?;
+    for job in jobs.iter_mut() {
+        job.add_default_headers(&header_map);
+        if !job.header_map.contains_key(header::AUTHORIZATION) {
+            if let Some(authorization) = netrc.authorization(&job.url) {
+                job.header_map.insert(header::AUTHORIZATION, authorization);
             }
-        });
+        }
+    }
+
+    let speed_limit = SpeedLimit::new(args.speed_limit);
+    if let Some(limit_schedule) = args.limit_schedule.clone() {
+        limit_schedule.start(LocalClock, speed_limit.clone(), args.speed_limit);
     }
+    let global_speed_limiter = Arc::new(GlobalSpeedLimiter::new(args.global_speed_limit));
+    let client = proxy::build_client(args.proxy.as_ref(), args.no_proxy, args.connect_timeout)?;
+    let mut file_paths = HashSet::new();
+    let mut downloads = Vec::with_capacity(jobs.len());
+    for (id, job) in jobs.into_iter().enumerate() {
+        let file_path = unique_file_path(resolve_file_path(&job, &args)?, &file_paths);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        downloads.push(Arc::new(Download::new(id, job, &file_path, client.clone(), speed_limit.subscribe(), global_speed_limiter.clone(), &args)));
+        file_paths.insert(file_path);
+    }
+    let control_socket = args.control_socket.clone()
+        .map(|path| ControlSocket::bind(path, Controls {
+            speed_limit: speed_limit.clone(),
+            global_speed_limiter: global_speed_limiter.clone(),
+            downloads: downloads.clone(),
+        }))
+        .transpose()?;
 
-    let dec = finished_future.await?;
+    let (interrupt_sender, mut interrupt_receiver) = sync::mpsc::unbounded_channel();
+    let view = if args.tui {
+        let tui = Tui::new(downloads.clone(), speed_limit.clone(), global_speed_limiter.clone(), interrupt_sender);
+        Some(tokio::task::spawn_blocking(move || tui.run()))
+    } else if !args.silence {
+        if args.progress {
+            execute!(
+                stdout(),
+                Hide
+            )?;
+        }
+        Some(tokio::spawn(ProgressView::new(downloads.clone(), speed_limit.clone(), global_speed_limiter.clone(), args.progress).run()))
+    } else {
+        None
+    };
 
-    if !args.silence {
+    let mut run_all = Box::pin(scheduler::run_all(&downloads, args.max_concurrent));
+    let mut interrupted_exit_code = None;
+    let results = tokio::select! {
+        results = &mut run_all => results?,
+        exit_code = async {
+            tokio::select! {
+                exit_code = signal::interrupted(&mut interrupt_receiver) => exit_code,
+                _ = async {
+                    match args.max_time {
+                        Some(max_time) => tokio::time::sleep(max_time).await,
+                        None => std::future::pending().await,
+                    }
+                } => Ok(signal::EXIT_TIMEOUT),
+            }
+        } => {
+            let exit_code = exit_code?;
+            interrupted_exit_code = Some(exit_code);
+            for download in downloads.iter() {
+                download.stop(false);
+            }
+            // a second signal skips waiting for the downloads to save their state, but gives
+            // them a moment to write what they received before the resume data goes
+            let is_tui = args.tui;
+            let downloads = downloads.clone();
+            tokio::spawn(async move {
+                let exit_code = signal::interrupted(&mut interrupt_receiver).await.unwrap_or(exit_code);
+                let _ = tokio::time::timeout(FORCED_EXIT_WAIT, async {
+                    while !downloads.iter().all(|n| n.status().is_end()) {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                }).await;
+                for download in downloads.iter() {
+                    download.abandon();
+                }
+                if is_tui {
+                    tui::restore_terminal();
+                } else {
+                    let _ = execute!(stdout(), Show);
+                }
+                std::process::exit(exit_code);
+            });
+            run_all.await?
+        }
+    };
+    if let Some(view) = view {
+        view.await??;
+    }
+    if !args.tui && !args.silence && args.progress {
         execute!(
-        stdout(),
-        MoveToNextLine(1),
-        Clear(ClearType::CurrentLine),
-        MoveToPreviousLine(1),
-        Clear(ClearType::CurrentLine),
-        MoveToColumn(0),
-        Print(format!("{:?}",dec)),
-    )?;
-        println!("Save To: {}", file_path.display());
+            stdout(),
+            Show
+        )?;
+    }
+    drop(control_socket);
+
+    if let Some(exit_code) = interrupted_exit_code {
+        if !args.silence {
+            if exit_code == signal::EXIT_TIMEOUT {
+                eprintln!("Stopped after --max-time, run the same command again to resume");
+            } else {
+                eprintln!("Interrupted, run the same command again to resume");
+            }
+        }
+        std::process::exit(exit_code);
+    }
+
+    let failed_count = results.iter().filter(|n| n.is_err()).count();
+    if failed_count > 0 {
+        anyhow::bail!("{failed_count} of {} downloads failed", results.len());
+    }
+    if invalid_line_count > 0 {
+        anyhow::bail!("input file has {invalid_line_count} invalid lines");
     }
     Ok(())
 }
 
-
-pub struct ProgressBar {
-    bar_buf: String,
-    buf: String,
-    start_instant: Instant,
-    bar_width: usize,
+/// The options of the config file come first, and are left out where the command line
+/// sets them, so the command line overrides them.
+fn parse_args() -> Result<Args> {
+    let mut command_line = std::env::args_os().collect::<Vec<_>>();
+    let config_file = match config::path_from_args(&command_line) {
+        Some(config_file) => config_file,
+        None => match config::default_path() {
+            Some(config_file) if config_file.exists() => config_file,
+            _ => return Ok(Args::parse_from(command_line)),
+        },
+    };
+    let command = Args::command();
+    let config_args = config::without_overridden(config::read(&config_file, &command)?, &command_line, command);
+    let program = if command_line.is_empty() { OsString::from("hd") } else { command_line.remove(0) };
+    Ok(Args::parse_from(std::iter::once(program).chain(config_args).chain(command_line)))
 }
 
-impl ProgressBar {
-    pub fn new(max_width: usize) -> Self {
-        Self {
-            buf: String::new(),
-            bar_buf: String::new(),
-            start_instant: Instant::now(),
-            bar_width: crossterm::terminal::size().ok()
-                .map(|(cols, _rows)| usize::from(cols))
-                .unwrap_or(0).min(max_width),
-        }
+fn default_save_dir() -> Result<PathBuf> {
+    match std::env::var_os("XDG_DOWNLOAD_DIR") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => Ok(std::env::current_dir()?),
     }
-    fn update(&mut self, downloaded_len: u64, total_len: u64, speed: u64) -> Result<&str, std::fmt::Error> {
-        let progress = (downloaded_len * 100 / total_len) as usize;
-
-        let (downloaded_len_size, downloaded_len_unit) = Self::byte_unit(downloaded_len);
-        let (total_len_size, total_len_unit) = Self::byte_unit(total_len);
-        let (speed_size, speed_unit) = Self::byte_unit(speed);
-
+}
 
-        self.bar_buf.clear();
-        self.buf.clear();
-        let duration = self.start_instant.elapsed();
-        write!(self.bar_buf, "{speed_size:.2} {speed_unit}/s - {progress} % - elapsed: {duration:.2?} ")?;
-        write!(self.buf, "{downloaded_len_size:.2} {downloaded_len_unit} / {total_len_size:.2} {total_len_unit}")?;
-        for _ in 0..(self.bar_width - self.bar_buf.len() - self.buf.len()) {
-            self.bar_buf.push(' ');
-        }
-        writeln!(self.bar_buf, "{}", self.buf)?;
+/// Parses `1.5`, `500ms`, `2s`, `1m` or `1h`, a plain number is seconds.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let index = value.find(|n: char| !n.is_ascii_digit() && n != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(index);
+    let number = number.parse::<f64>()
+        .map_err(|_| anyhow::Error::msg(format!("invalid duration `{value}`")))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        unit => anyhow::bail!("invalid duration unit `{unit}`, expected ms, s, m or h"),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| anyhow::Error::msg(format!("duration `{value}` is too long")))
+}
 
-        let bar_p_width = self.bar_width - 2;
-        let progress_width = progress * bar_p_width / 100;
-        self.bar_buf.push('[');
-        for _ in 0..progress_width {
-            self.bar_buf.push('█');
-        }
-        for _ in progress_width..bar_p_width {
-            self.bar_buf.push(' ');
+/// Headers sent with every request, the dedicated options take precedence over -H.
+/// The library sends one value per name, so a repeated -H replaces the earlier one.
+fn request_headers(args: &Args) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in args.headers.iter() {
+        header_map.insert(name, value.clone());
+    }
+    for (name, value) in [
+        (header::USER_AGENT, &args.user_agent),
+        (header::REFERER, &args.referer),
+        (header::COOKIE, &args.cookie),
+    ] {
+        if let Some(value) = value {
+            header_map.insert(name, value.clone());
         }
-        self.bar_buf.push(']');
-
-        Ok(&self.bar_buf)
     }
 
+    let bearer_token = match &args.bearer_token_file {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .map_err(|err| anyhow::Error::msg(format!("{}: {err}", path.display())))?
+            .trim()
+            .to_string()),
+        None => args.bearer_token.clone(),
+    };
+    if let Some(user) = &args.user {
+        let (user, password) = user.split_once(':').unwrap_or((user, ""));
+        header_map.insert(header::AUTHORIZATION, auth::basic_auth(user, password));
+    } else if let Some(bearer_token) = bearer_token {
+        header_map.insert(header::AUTHORIZATION, auth::bearer_auth(&bearer_token)?);
+    }
+    Ok(header_map)
+}
 
-    fn byte_unit(bytes_count: u64) -> (f32, &'static str) {
-        const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
-
-        let mut i = 0;
-        let mut bytes_count = bytes_count as f32;
-        while bytes_count >= 1024.0 && i < UNITS.len() - 1 {
-            i += 1;
-            bytes_count /= 1024.0;
+fn resolve_file_path(job: &Job, args: &Args) -> Result<PathBuf> {
+    let save_dir = match job.dir.as_ref().or(args.dir.as_ref()) {
+        Some(dir) => dir.clone(),
+        None => default_save_dir()?,
+    };
+    let file_path = match job.output.as_ref().or(args.output.as_ref()) {
+        Some(output) => save_dir.join(output),
+        None => {
+            let file_name = job.url.file_name();
+            save_dir.join(if file_name.is_empty() { "index.html" } else { file_name.as_ref() })
         }
-        (bytes_count, UNITS[i])
+    };
+    if file_path.file_name().is_none() {
+        anyhow::bail!("invalid output file path: {}", file_path.display());
     }
-}
\ No newline at end of file
+    Ok(file_path)
+}
+
+// Two URLs with the same file name must not write into the same file.
+fn unique_file_path(file_path: PathBuf, used: &HashSet<PathBuf>) -> PathBuf {
+    if !used.contains(&file_path) {
+        return file_path;
+    }
+    (1..)
+        .map(|n| {
+            let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(format!(".{n}"));
+            file_path.with_file_name(file_name)
+        })
+        .find(|n| !used.contains(n))
+        .unwrap()
+}
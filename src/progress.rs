@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{stdout, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossterm::cursor::{MoveToColumn, MoveToPreviousLine};
+use crossterm::queue;
+use crossterm::style::Print;
+use crossterm::terminal::{Clear, ClearType};
+
+use crate::download::{Download, DownloadStatus};
+use crate::layout;
+use crate::limit::{format_byte_count_per, format_speed_limit, GlobalSpeedLimiter, SpeedLimit};
+use crate::segment::{SegmentMap, SegmentState};
+use crate::size::BYTE_UNITS;
+
+/// Line based progress output: finished downloads are reported above a stack of
+/// progress bars (one per active download) and an overall line.
+pub struct ProgressView {
+    downloads: Vec<Arc<Download>>,
+    speed_limit: SpeedLimit,
+    global_speed_limiter: Arc<GlobalSpeedLimiter>,
+    show_progress: bool,
+    bars: HashMap<usize, ProgressBar>,
+    verifying_bars: HashMap<usize, ProgressBar>,
+    reported: Vec<bool>,
+    messages: String,
+    frame: String,
+    /// The width of each line of the last frame.
+    drawn_line_widths: Vec<usize>,
+}
+
+impl ProgressView {
+    pub fn new(downloads: Vec<Arc<Download>>, speed_limit: SpeedLimit, global_speed_limiter: Arc<GlobalSpeedLimiter>, show_progress: bool) -> Self {
+        Self {
+            reported: vec![false; downloads.len()],
+            downloads,
+            speed_limit,
+            global_speed_limiter,
+            show_progress,
+            bars: HashMap::new(),
+            verifying_bars: HashMap::new(),
+            messages: String::new(),
+            frame: String::new(),
+            drawn_line_widths: Vec::new(),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        loop {
+            let is_all_end = self.downloads.iter().all(|n| n.status().is_end());
+            self.draw()?;
+            if is_all_end {
+                if !self.drawn_line_widths.is_empty() {
+                    println!();
+                }
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        self.messages.clear();
+        for (download, reported) in self.downloads.iter().zip(self.reported.iter_mut()) {
+            if *reported {
+                continue;
+            }
+            *reported = write_result(&mut self.messages, download)?;
+        }
+
+        let width = layout::terminal_width();
+        self.frame.clear();
+        if self.show_progress {
+            self.write_bars(width)?;
+            // a line wider than the terminal wraps, which would throw off moving back up
+            self.frame = self.frame.lines().map(|n| layout::truncate(n, width)).collect::<Vec<_>>().join("\n");
+        }
+        if self.messages.is_empty() && self.frame.is_empty() && self.drawn_line_widths.is_empty() {
+            return Ok(());
+        }
+
+        // after the terminal got narrower, it wrapped the lines of the last frame
+        let drawn_rows = self.drawn_line_widths.iter().map(|n| n.div_ceil(width).max(1)).sum::<usize>();
+        let mut stdout = stdout();
+        if drawn_rows > 0 {
+            queue!(stdout, MoveToColumn(0))?;
+            if drawn_rows > 1 {
+                queue!(stdout, MoveToPreviousLine(u16::try_from(drawn_rows - 1).unwrap_or(u16::MAX)))?;
+            }
+            queue!(stdout, Clear(ClearType::FromCursorDown))?;
+        }
+        queue!(
+            stdout,
+            Print(&self.messages),
+            Print(&self.frame)
+        )?;
+        stdout.flush()?;
+        self.drawn_line_widths = self.frame.lines().map(layout::width).collect();
+        Ok(())
+    }
+
+    fn write_bars(&mut self, width: usize) -> Result<()> {
+        let is_multiple = self.downloads.len() > 1;
+        let (mut finished_count, mut downloaded_len, mut total_len, mut speed) = (0, 0, 0, 0);
+        let mut is_total_len_unknown = false;
+        for download in self.downloads.iter() {
+            let status = download.status();
+            if let DownloadStatus::Retrying { error, .. } = &status {
+                if !self.frame.is_empty() {
+                    self.frame.push('\n');
+                }
+                write!(self.frame, "{}: {status}: {error}", download.file_name())?;
+                continue;
+            }
+            match status {
+                DownloadStatus::Finished => finished_count += 1,
+                DownloadStatus::Downloading | DownloadStatus::Verifying => {}
+                _ => continue,
+            }
+            downloaded_len += download.downloaded_len();
+            match download.total_len() {
+                Some(download_total_len) => total_len += download_total_len,
+                None => is_total_len_unknown = true,
+            }
+            let buf = match status {
+                DownloadStatus::Downloading => {
+                    speed += download.smoothed_speed();
+                    let bar = self.bars.entry(download.id).or_insert_with(|| {
+                        let bar = ProgressBar::new(MAX_BAR_WIDTH);
+                        if is_multiple { bar.with_title(download.file_name()) } else { bar }
+                    });
+                    bar.update(width, &Progress::of(download))?
+                }
+                DownloadStatus::Verifying => {
+                    let bar = self.verifying_bars.entry(download.id).or_insert_with(|| {
+                        ProgressBar::new(MAX_BAR_WIDTH).with_title(if is_multiple {
+                            format!("verifying {}…", download.file_name())
+                        } else {
+                            "verifying…".to_string()
+                        })
+                    });
+                    let verified_len = download.verified_len();
+                    // a download of unknown size is complete by now
+                    let total_len = download.total_len().unwrap_or(download.downloaded_len());
+                    let speed = (verified_len as f64 / bar.elapsed().as_secs_f64().max(0.001)) as u64;
+                    bar.update(width, &Progress {
+                        len: verified_len,
+                        total_len: Some(total_len),
+                        speed,
+                        average_speed: None,
+                        eta: (speed > 0).then(|| Duration::from_secs(total_len.saturating_sub(verified_len) / speed)),
+                        segments: None,
+                    })?
+                }
+                _ => continue,
+            };
+            if !self.frame.is_empty() {
+                self.frame.push('\n');
+            }
+            self.frame.push_str(buf);
+        }
+        if is_multiple {
+            let (downloaded_len_size, downloaded_len_unit) = ProgressBar::byte_unit(downloaded_len);
+            let (total_len_size, total_len_unit) = ProgressBar::byte_unit(total_len);
+            let (speed_size, speed_unit) = ProgressBar::byte_unit(speed);
+            if !self.frame.is_empty() {
+                self.frame.push('\n');
+            }
+            write!(
+                self.frame,
+                "Total: {finished_count}/{} - {speed_size:.2} {speed_unit}/s - {downloaded_len_size:.2} {downloaded_len_unit} / {}{total_len_size:.2} {total_len_unit}",
+                self.downloads.len(),
+                if is_total_len_unknown { "≥ " } else { "" }
+            )?;
+            if let Some(byte_count_per) = self.global_speed_limiter.get() {
+                write!(self.frame, " (limit {})", format_byte_count_per(Some(byte_count_per)))?;
+            }
+        }
+        if self.speed_limit.rule().is_some() && !self.frame.is_empty() {
+            write!(self.frame, "\nSpeed limit: {}", format_speed_limit(&self.speed_limit))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the outcome of an ended download, returns `false` if it has not ended yet.
+pub fn write_result(buf: &mut String, download: &Download) -> Result<bool, std::fmt::Error> {
+    match download.status() {
+        DownloadStatus::Finished => {
+            let resumed_len = download.resumed_len();
+            let downloaded_len = download.downloaded_len().saturating_sub(resumed_len);
+            let download_time = download.download_time();
+            let (size, unit) = ProgressBar::byte_unit(downloaded_len);
+            let (speed_size, speed_unit) = ProgressBar::byte_unit((downloaded_len as f64 / download_time.as_secs_f64().max(0.001)) as u64);
+            write!(buf, "Finished: {size:.2} {unit} in {download_time:.2?}, {speed_size:.2} {speed_unit}/s on average")?;
+            if resumed_len > 0 {
+                let (resumed_size, resumed_unit) = ProgressBar::byte_unit(resumed_len);
+                write!(buf, ", resumed at {resumed_size:.2} {resumed_unit}")?;
+            }
+            writeln!(buf, "\nSave To: {}", download.file_path().display())?;
+            if download.is_auto() {
+                let (chunk_size, chunk_size_unit) = ProgressBar::byte_unit(download.chunk_size().get() as u64);
+                let connection_count = download.connection_count();
+                writeln!(
+                    buf,
+                    "Auto: {connection_count} connection{}, {chunk_size:.2} {chunk_size_unit} chunks",
+                    if connection_count.get() == 1 { "" } else { "s" }
+                )?;
+            }
+            for (algorithm, digest) in download.digests() {
+                writeln!(buf, "{algorithm}: {digest}")?;
+            }
+        }
+        DownloadStatus::Cancelled if download.file_path().exists() => {
+            writeln!(buf, "Cancelled\nSave To: {}", download.file_path().display())?;
+            let archive_file_path = download.archive_file_path();
+            if archive_file_path.exists() {
+                writeln!(buf, "Resume data: {}", archive_file_path.display())?;
+            }
+        }
+        DownloadStatus::Cancelled => {
+            writeln!(buf, "Cancelled: {}", download.file_name())?;
+        }
+        DownloadStatus::Failed(err) => {
+            writeln!(buf, "Download failed: {}\nUrl: {}", err, download.url)?;
+        }
+        DownloadStatus::Queued
+        | DownloadStatus::Downloading
+        | DownloadStatus::Paused
+        | DownloadStatus::Retrying { .. }
+        | DownloadStatus::Verifying => return Ok(false),
+    }
+    Ok(true)
+}
+
+// bars of the line based output are no wider than this
+const MAX_BAR_WIDTH: usize = 62;
+// how long the block of an unknown size bar takes to move by one cell
+const BOUNCE_STEP: Duration = Duration::from_millis(50);
+// narrower than this the bar is left out
+const MIN_BAR_WIDTH: usize = 10;
+// a title is left out rather than shortened to less than this
+const MIN_TITLE_WIDTH: usize = 4;
+
+/// What a [`ProgressBar`] shows.
+pub struct Progress {
+    pub len: u64,
+    pub total_len: Option<u64>,
+    /// Bytes per second.
+    pub speed: u64,
+    pub average_speed: Option<u64>,
+    /// Estimated time left.
+    pub eta: Option<Duration>,
+    /// Draws the bar as a map of what is done and left to each connection.
+    pub segments: Option<SegmentMap>,
+}
+
+impl Progress {
+    pub fn of(download: &Download) -> Self {
+        Self {
+            len: download.downloaded_len(),
+            total_len: download.total_len(),
+            speed: download.smoothed_speed(),
+            average_speed: Some(download.average_speed()).filter(|n| *n > 0),
+            eta: download.eta(),
+            segments: download.segment_map(),
+        }
+    }
+}
+
+pub struct ProgressBar {
+    title: Option<String>,
+    buf: String,
+    start_instant: Instant,
+    max_bar_width: usize,
+    /// The cells of the last bar drawn from a segment map.
+    cells: Vec<SegmentState>,
+}
+
+impl ProgressBar {
+    pub fn new(max_bar_width: usize) -> Self {
+        Self {
+            title: None,
+            buf: String::new(),
+            start_instant: Instant::now(),
+            max_bar_width,
+            cells: Vec::new(),
+        }
+    }
+
+    pub fn with_title(mut self, title: String) -> Self {
+        self.title = Some(title);
+        self
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start_instant.elapsed()
+    }
+
+    /// Draws the bar for `width` cells. What does not fit is left out, the average speed
+    /// and the elapsed time first, then the time left, the speed, the end of the title and
+    /// finally the bar itself.
+    ///
+    /// Without a `total_len` a block bounces back and forth in the bar, which fills up
+    /// normally once the size is known. With `segments` each cell of the bar shows the
+    /// state of its part of the file instead.
+    pub fn update(&mut self, width: usize, progress: &Progress) -> Result<&str, std::fmt::Error> {
+        let Progress { len: downloaded_len, total_len, speed, average_speed, eta, ref segments } = *progress;
+        let (downloaded_len_size, downloaded_len_unit) = Self::byte_unit(downloaded_len);
+        let (speed_size, speed_unit) = Self::byte_unit(speed);
+        let duration = self.start_instant.elapsed();
+        let progress = total_len.map(|total_len| (downloaded_len * 100 / total_len.max(1)).min(100) as usize);
+
+        let mut sizes = format!("{downloaded_len_size:.2} {downloaded_len_unit}");
+        if let Some(total_len) = total_len {
+            let (total_len_size, total_len_unit) = Self::byte_unit(total_len);
+            write!(sizes, " / {total_len_size:.2} {total_len_unit}")?;
+        }
+        let mut title = self.title.clone();
+        let mut speed = Some(format!("{speed_size:.2} {speed_unit}/s"));
+        let progress_text = progress.map(|n| format!("{n} %"));
+        let mut eta = eta.map(|n| format!("ETA {}", format_duration(n)));
+        let mut average_speed = average_speed.map(|n| {
+            let (size, unit) = Self::byte_unit(n);
+            format!("avg {size:.2} {unit}/s")
+        });
+        let mut elapsed = Some(format!("elapsed: {duration:.2?}"));
+        let info = loop {
+            let info = [
+                title.as_deref(),
+                speed.as_deref(),
+                progress_text.as_deref(),
+                eta.as_deref(),
+                average_speed.as_deref(),
+                elapsed.as_deref(),
+            ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" - ");
+            let overflow = (layout::width(&info) + 1 + layout::width(&sizes)).saturating_sub(width);
+            if overflow == 0 {
+                break info;
+            }
+            if average_speed.take().is_some() || elapsed.take().is_some() || eta.take().is_some() || speed.take().is_some() {
+                continue;
+            }
+            match title.take() {
+                Some(text) if layout::width(&text) >= overflow + MIN_TITLE_WIDTH => {
+                    title = Some(layout::truncate(&text, layout::width(&text) - overflow));
+                }
+                Some(_) => {}
+                None => break info,
+            }
+        };
+
+        // the sizes line up with the end of the bar
+        let bar_width = width.min(self.max_bar_width);
+        let padding = bar_width.saturating_sub(layout::width(&info) + layout::width(&sizes)).max(1);
+        let line = format!("{info}{}{sizes}", " ".repeat(padding));
+        self.buf.clear();
+        self.buf.push_str(&layout::truncate(line.trim_start(), width));
+
+        self.cells.clear();
+        if bar_width >= MIN_BAR_WIDTH {
+            let bar_p_width = bar_width - 2;
+            self.buf.push_str("\n[");
+            match (progress, segments) {
+                (Some(_), Some(segments)) => {
+                    self.cells = segments.cells(bar_p_width);
+                    self.buf.extend(self.cells.iter().map(|n| n.glyph()));
+                }
+                (Some(progress), None) => {
+                    let progress_width = progress * bar_p_width / 100;
+                    self.buf.push_str(&"█".repeat(progress_width));
+                    self.buf.push_str(&" ".repeat(bar_p_width - progress_width));
+                }
+                (None, _) => self.buf.push_str(&Self::bounce(bar_p_width, duration)),
+            }
+            self.buf.push(']');
+        }
+
+        Ok(&self.buf)
+    }
+
+    /// The cells of the bar last drawn by [`ProgressBar::update`] if it was drawn from a
+    /// segment map, in the order of the characters between its brackets.
+    pub fn cells(&self) -> &[SegmentState] {
+        &self.cells
+    }
+
+    /// `width` cells with a block that moves from one end to the other and back over time.
+    pub fn bounce(width: usize, elapsed: Duration) -> String {
+        let block_width = (width / 6).max(1).min(width);
+        let travel = width - block_width;
+        let step = (elapsed.as_millis() / BOUNCE_STEP.as_millis()) as usize % (2 * travel).max(1);
+        let position = if step <= travel { step } else { 2 * travel - step };
+        format!("{}{}{}", " ".repeat(position), "█".repeat(block_width), " ".repeat(travel - position))
+    }
+
+
+    pub fn byte_unit(bytes_count: u64) -> (f32, &'static str) {
+        let mut i = 0;
+        let mut bytes_count = bytes_count as f32;
+        while bytes_count >= 1024.0 && i < BYTE_UNITS.len() - 1 {
+            i += 1;
+            bytes_count /= 1024.0;
+        }
+        (bytes_count, BYTE_UNITS[i])
+    }
+}
+/// `01:05`, or `1:01:05` from an hour on.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes:02}:{secs:02}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+    const TITLE: &str = "download-title.bin";
+
+    fn progress(total_len: Option<u64>, segments: Option<SegmentMap>) -> Progress {
+        Progress {
+            len: 5 * MIB,
+            total_len,
+            speed: MIB,
+            average_speed: Some(2 * MIB),
+            eta: Some(Duration::from_secs(5)),
+            segments,
+        }
+    }
+
+    fn segments() -> SegmentMap {
+        let mut segments = SegmentMap::new(10 * MIB);
+        segments.add(5 * MIB..8 * MIB, SegmentState::Downloading(1));
+        segments.add(8 * MIB..10 * MIB, SegmentState::Pending);
+        segments
+    }
+
+    #[test]
+    fn any_width() {
+        let titles = [None, Some(TITLE), Some("日本語のファイル名.zip"), Some("")];
+        let progresses = [
+            || progress(Some(10 * MIB), None),
+            || progress(None, None),
+            || progress(Some(10 * MIB), Some(segments())),
+            || progress(Some(0), None),
+        ];
+        for title in titles {
+            for progress in progresses {
+                for width in [0, 1, 2, MIN_BAR_WIDTH - 1, MIN_BAR_WIDTH, MIN_BAR_WIDTH + 1].into_iter().chain(0..=120) {
+                    let mut bar = ProgressBar::new(MAX_BAR_WIDTH);
+                    if let Some(title) = title {
+                        bar = bar.with_title(title.to_string());
+                    }
+                    let lines = bar.update(width, &progress()).unwrap().to_string();
+                    let lines = lines.split('\n').collect::<Vec<_>>();
+                    for line in lines.iter() {
+                        assert!(layout::width(line) <= width, "{line:?} in {width}");
+                    }
+                    assert_eq!(lines.len(), if width >= MIN_BAR_WIDTH { 2 } else { 1 }, "{lines:?} in {width}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drop_order() {
+        for width in 0..=140 {
+            let mut bar = ProgressBar::new(MAX_BAR_WIDTH).with_title(TITLE.to_string());
+            let line = bar.update(width, &progress(Some(10 * MIB), None)).unwrap().lines().next().unwrap_or("").to_string();
+            // what is dropped first is only there if everything dropped after it is
+            let parts = [
+                line.contains("avg 2.00 MiB/s"),
+                line.contains("elapsed: "),
+                line.contains("ETA 00:05"),
+                line.contains("1.00 MiB/s"),
+                line.contains(TITLE),
+                line.starts_with("down"),
+            ];
+            for pair in parts.windows(2) {
+                assert!(!pair[0] || pair[1], "{line:?} in {width}");
+            }
+            if width >= 120 {
+                assert!(parts.iter().all(|n| *n), "{line:?} in {width}");
+            }
+        }
+    }
+}
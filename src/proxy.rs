@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::Proxy;
+use url::Url;
+
+/// Builds the client every download shares. Requests go through `proxy`, otherwise
+/// through the `HTTP_PROXY`, `HTTPS_PROXY` or `ALL_PROXY` environment variables, except
+/// for hosts listed in `NO_PROXY`. With `no_proxy` every request connects directly.
+pub fn build_client(proxy: Option<&Url>, no_proxy: bool, connect_timeout: Option<Duration>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if no_proxy {
+        return Ok(builder.no_proxy().build()?);
+    }
+
+    let (http_proxy, https_proxy) = match proxy {
+        Some(proxy) => (Some(proxy.clone()), Some(proxy.clone())),
+        None => {
+            let all_proxy = env_proxy(&["ALL_PROXY", "all_proxy"])?;
+            (
+                env_proxy(&["HTTP_PROXY", "http_proxy"])?.or_else(|| all_proxy.clone()),
+                env_proxy(&["HTTPS_PROXY", "https_proxy"])?.or(all_proxy),
+            )
+        }
+    };
+    // reqwest rejects unsupported schemes only when the proxy is used, check them up front
+    for proxy in http_proxy.iter().chain(https_proxy.iter()) {
+        Proxy::all(proxy.clone()).map_err(|err| anyhow::Error::msg(format!("invalid proxy `{}`: {err}", redact(proxy))))?;
+    }
+    let no_proxy = NoProxy::from_env();
+    Ok(builder.proxy(Proxy::custom(move |url| {
+        if no_proxy.matches(url) {
+            return None;
+        }
+        match url.scheme() {
+            "https" => https_proxy.clone(),
+            _ => http_proxy.clone(),
+        }
+    })).build()?)
+}
+
+/// The first of `names` that is set, a proxy without a scheme is an HTTP proxy.
+fn env_proxy(names: &[&str]) -> Result<Option<Url>> {
+    let Some(value) = names.iter().find_map(|n| std::env::var(n).ok().filter(|n| !n.is_empty())) else {
+        return Ok(None);
+    };
+    let value = if value.contains("://") { value } else { format!("http://{value}") };
+    Url::parse(&value)
+        .map(Some)
+        .map_err(|err| anyhow::Error::msg(format!("invalid proxy in {}: {err}", names[0])))
+}
+
+fn redact(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+/// Hosts from `NO_PROXY`: `*`, domains (matching their subdomains too), IP addresses
+/// and networks like `192.168.0.0/16`.
+#[derive(Default)]
+struct NoProxy {
+    entries: Vec<String>,
+}
+
+impl NoProxy {
+    fn from_env() -> Self {
+        Self::new(&std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")).unwrap_or_default())
+    }
+
+    /// Parses a comma separated list, IPv6 addresses may be in brackets like in a URL.
+    fn new(value: &str) -> Self {
+        Self {
+            entries: value.split(',')
+                .map(|n| n.trim().trim_start_matches('.').replace(['[', ']'], "").to_ascii_lowercase())
+                .filter(|n| !n.is_empty())
+                .collect(),
+        }
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+        self.entries.iter().any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            if let Some(ip) = ip {
+                return match entry.split_once('/') {
+                    Some((network, prefix_len)) => match (network.parse::<IpAddr>(), prefix_len.parse::<u32>()) {
+                        (Ok(network), Ok(prefix_len)) => is_in_network(ip, network, prefix_len),
+                        _ => false,
+                    },
+                    None => entry.parse::<IpAddr>().map(|n| n == ip).unwrap_or(false),
+                };
+            }
+            host == *entry || host.strip_suffix(entry.as_str()).map(|n| n.ends_with('.')).unwrap_or(false)
+        })
+    }
+}
+
+fn is_in_network(ip: IpAddr, network: IpAddr, prefix_len: u32) -> bool {
+    let (ip, network, bits) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => (u32::from(ip) as u128, u32::from(network) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len.min(bits);
+    ip >> shift == network >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(no_proxy: &str, url: &str) -> bool {
+        NoProxy::new(no_proxy).matches(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn domains() {
+        assert!(matches("example.com", "http://example.com/file"));
+        assert!(matches("example.com", "http://sub.example.com/file"));
+        assert!(matches(".example.com", "http://a.b.example.com/file"));
+        assert!(matches("Example.COM", "http://example.com:8080/file"));
+        assert!(matches("other.org, example.com", "http://EXAMPLE.com/file"));
+        assert!(!matches("example.com", "http://badexample.com/file"));
+        assert!(!matches("example.com", "http://example.com.evil.org/file"));
+        assert!(!matches("sub.example.com", "http://example.com/file"));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(matches("*", "http://example.com/file"));
+        assert!(matches("*", "http://127.0.0.1/file"));
+        assert!(!matches("", "http://example.com/file"));
+        assert!(!matches(" , ", "http://example.com/file"));
+    }
+
+    #[test]
+    fn ip_addresses() {
+        assert!(matches("127.0.0.1", "http://127.0.0.1:8080/file"));
+        assert!(!matches("127.0.0.1", "http://127.0.0.2/file"));
+        assert!(matches("::1", "http://[::1]/file"));
+        assert!(matches("[::1]", "http://[::1]:8080/file"));
+        assert!(!matches("::1", "http://[::2]/file"));
+        // a domain entry does not match an address
+        assert!(!matches("0.1", "http://127.0.0.1/file"));
+    }
+
+    #[test]
+    fn networks() {
+        assert!(matches("192.168.0.0/16", "http://192.168.1.20/file"));
+        assert!(!matches("192.168.0.0/16", "http://192.169.0.1/file"));
+        assert!(matches("10.0.0.0/8", "http://10.255.255.255/file"));
+        assert!(matches("10.1.2.3/32", "http://10.1.2.3/file"));
+        assert!(!matches("10.1.2.3/32", "http://10.1.2.4/file"));
+        assert!(matches("0.0.0.0/0", "http://8.8.8.8/file"));
+        assert!(matches("fd00::/8", "http://[fd12::1]/file"));
+        assert!(!matches("fd00::/8", "http://[fe80::1]/file"));
+        assert!(matches("[fd00::]/8", "http://[fd12::1]/file"));
+        // IPv4 and IPv6 never match each other
+        assert!(!matches("::/0", "http://127.0.0.1/file"));
+        assert!(!matches("0.0.0.0/0", "http://[::1]/file"));
+        assert!(!matches("10.0.0.0/x", "http://10.0.0.1/file"));
+        assert!(!matches("192.168.0.0/16", "http://example.com/file"));
+    }
+
+    #[test]
+    fn prefix_lengths() {
+        let ip = "10.1.2.3".parse().unwrap();
+        let network = "10.1.0.0".parse().unwrap();
+        assert!(is_in_network(ip, network, 16));
+        assert!(!is_in_network(ip, network, 24));
+        assert!(is_in_network(ip, network, 0));
+        // longer than the address, only the address itself
+        assert!(!is_in_network(ip, network, 64));
+        assert!(is_in_network(ip, ip, 64));
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+use http_downloader::{DownloadError, DownloadStartError};
+use rand::Rng;
+use reqwest::header::{self, HeaderMap};
+use reqwest::StatusCode;
+
+/// The server answered with an error status.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl HttpStatusError {
+    pub fn new(status: StatusCode, header_map: &HeaderMap) -> Self {
+        Self {
+            status,
+            retry_after: retry_after(header_map),
+        }
+    }
+}
+
+impl Display for HttpStatusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// A connection received nothing for --read-timeout.
+#[derive(Debug)]
+pub struct ReadTimeoutError(pub Duration);
+
+impl Display for ReadTimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nothing received for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ReadTimeoutError {}
+
+/// A chunk received something else than its range, although the server answers it
+/// without an error now.
+#[derive(Debug)]
+pub struct ChunkLenError(pub Range<u64>);
+
+impl Display for ChunkLenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0.end.checked_sub(1).filter(|end| *end >= self.0.start) {
+            Some(end) => write!(f, "bytes {}-{} came back with the wrong length", self.0.start, end),
+            None => write!(f, "the chunk at byte {} came back with the wrong length", self.0.start),
+        }
+    }
+}
+
+impl std::error::Error for ChunkLenError {}
+
+/// The library ended a download with another length than the server's file.
+#[derive(Debug)]
+pub struct IncompleteError {
+    pub downloaded_len: u64,
+    pub total_len: u64,
+}
+
+impl Display for IncompleteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "finished with {} of {} bytes", self.downloaded_len, self.total_len)
+    }
+}
+
+impl std::error::Error for IncompleteError {}
+
+/// `Retry-After` as either a number of seconds or an HTTP date.
+fn retry_after(header_map: &HeaderMap) -> Option<Duration> {
+    let value = header_map.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value).ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub wait: Duration,
+    pub max_wait: Duration,
+    pub retry_on: Vec<u16>,
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt` (starting at 1) after `err`, `None`
+    /// when `err` is permanent or the retries are used up.
+    pub fn delay(&self, err: &anyhow::Error, attempt: u32) -> Option<Duration> {
+        if attempt > self.retries || !self.is_transient(err) {
+            return None;
+        }
+        let backoff = self.wait
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_wait);
+        // jitter keeps downloads that failed together from retrying together
+        let backoff = rand::thread_rng().gen_range(backoff / 2..=backoff);
+        let retry_after = err.downcast_ref::<HttpStatusError>().and_then(|n| n.retry_after);
+        Some(backoff.max(retry_after.unwrap_or_default()))
+    }
+
+    fn is_transient(&self, err: &anyhow::Error) -> bool {
+        if let Some(err) = err.downcast_ref::<HttpStatusError>() {
+            return self.retry_on.contains(&err.status.as_u16());
+        }
+        err.is::<ReadTimeoutError>()
+            || err.is::<ChunkLenError>()
+            || err.is::<IncompleteError>()
+            || matches!(err.downcast_ref::<DownloadError>(), Some(DownloadError::HttpRequestFailed(_)))
+            || matches!(err.downcast_ref::<DownloadStartError>(), Some(DownloadStartError::HttpRequestFailed(_)))
+            || err.downcast_ref::<reqwest::Error>().is_some()
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime};
+
+use crate::limit::SpeedLimit;
+use crate::size;
+
+/// The local time of day, abstracted so a schedule can be run against any time.
+pub trait Clock: Send + Sync + 'static {
+    fn time_of_day(&self) -> NaiveTime;
+}
+
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn time_of_day(&self) -> NaiveTime {
+        Local::now().time()
+    }
+}
+
+/// `08:00-18:00=2M`, a range ending before it starts wraps around midnight and `0` is
+/// unlimited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduleRule {
+    start: NaiveTime,
+    end: NaiveTime,
+    byte_count_per: Option<usize>,
+}
+
+impl ScheduleRule {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            // also a range starting and ending at the same time covers the whole day
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for ScheduleRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid schedule rule `{s}`, expected `HH:MM-HH:MM=<limit>`");
+        let (range, limit) = s.trim().split_once('=').ok_or_else(invalid)?;
+        let (start, end) = range.trim().split_once('-').ok_or_else(invalid)?;
+        let parse_time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            byte_count_per: Some(size::parse(limit.trim())?).filter(|n| *n > 0),
+        })
+    }
+}
+
+impl Display for ScheduleRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// Speed limits by time of day, e.g. `08:00-18:00=2M,18:00-08:00=0`. The first rule
+/// containing the time applies, outside of all rules --speed-limit does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitSchedule {
+    rules: Vec<ScheduleRule>,
+}
+
+impl LimitSchedule {
+    pub fn active_rule(&self, time: NaiveTime) -> Option<&ScheduleRule> {
+        self.rules.iter().find(|n| n.contains(time))
+    }
+
+    /// Applies the active rule to `speed_limit` right away and again whenever another rule
+    /// becomes active, a limit changed by hand stays until then.
+    pub fn start(self, clock: impl Clock, speed_limit: SpeedLimit, default: Option<usize>) {
+        let apply = move |rule: Option<&ScheduleRule>| match rule {
+            Some(rule) => speed_limit.apply_schedule(rule.byte_count_per, Some(rule.to_string())),
+            None => speed_limit.apply_schedule(default, None),
+        };
+        let mut active_rule = self.active_rule(clock.time_of_day()).cloned();
+        apply(active_rule.as_ref());
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let rule = self.active_rule(clock.time_of_day());
+                if rule != active_rule.as_ref() {
+                    apply(rule);
+                    active_rule = rule.cloned();
+                }
+            }
+        });
+    }
+}
+
+impl FromStr for LimitSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s.split(',')
+            .filter(|n| !n.trim().is_empty())
+            .map(ScheduleRule::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if rules.is_empty() {
+            return Err("empty limit schedule".to_string());
+        }
+        Ok(Self { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M:%S").unwrap()
+    }
+
+    fn rule(value: &str) -> ScheduleRule {
+        value.parse().unwrap()
+    }
+
+    /// Starts at `start` and follows the tokio clock, which the tests pause.
+    struct FakeClock {
+        start: NaiveTime,
+        since: tokio::time::Instant,
+    }
+
+    impl Clock for FakeClock {
+        fn time_of_day(&self) -> NaiveTime {
+            self.start + chrono::Duration::from_std(self.since.elapsed()).unwrap()
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let rule = rule("08:00-18:00=2M");
+        assert!(!rule.contains(time("07:59:59")));
+        assert!(rule.contains(time("08:00:00")));
+        assert!(rule.contains(time("17:59:59")));
+        assert!(!rule.contains(time("18:00:00")));
+    }
+
+    #[test]
+    fn contains_across_midnight() {
+        let rule = rule("22:00-06:00=2M");
+        assert!(rule.contains(time("22:00:00")));
+        assert!(rule.contains(time("00:00:00")));
+        assert!(rule.contains(time("05:59:59")));
+        assert!(!rule.contains(time("06:00:00")));
+        assert!(!rule.contains(time("12:00:00")));
+    }
+
+    #[test]
+    fn contains_whole_day() {
+        let rule = rule("08:00-08:00=2M");
+        assert!(rule.contains(time("08:00:00")));
+        assert!(rule.contains(time("07:59:59")));
+        assert!(rule.contains(time("20:00:00")));
+    }
+
+    #[test]
+    fn active_rule_is_the_first() {
+        let schedule = "08:00-18:00=2M,00:00-00:00=0".parse::<LimitSchedule>().unwrap();
+        assert_eq!(schedule.active_rule(time("09:00:00")), Some(&rule("08:00-18:00=2M")));
+        assert_eq!(schedule.active_rule(time("19:00:00")), Some(&rule("00:00-00:00=0")));
+        assert_eq!(rule("00:00-00:00=0").byte_count_per, None);
+    }
+
+    #[test]
+    fn from_str_errors() {
+        for value in ["08:00-18:00", "08:00=2M", "8-18=2M", "08:00-24:00=2M", "08:00-18:00=fast"] {
+            assert!(value.parse::<ScheduleRule>().is_err(), "{value}");
+        }
+        assert_eq!(" , ".parse::<LimitSchedule>(), Err("empty limit schedule".to_string()));
+        assert!("08:00-18:00=2M,nope".parse::<LimitSchedule>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_follows_the_clock() {
+        let schedule = "08:00-18:00=2M".parse::<LimitSchedule>().unwrap();
+        let clock = FakeClock { start: time("07:59:58"), since: tokio::time::Instant::now() };
+        let speed_limit = SpeedLimit::new(None);
+        schedule.start(clock, speed_limit.clone(), Some(1024 * 1024));
+        assert_eq!(speed_limit.get(), Some(1024 * 1024));
+        assert_eq!(speed_limit.rule(), None);
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(speed_limit.get(), Some(2 * 1024 * 1024));
+        assert_eq!(speed_limit.rule().as_deref(), Some("08:00-18:00"));
+
+        // a limit set by hand stays until another rule becomes active
+        speed_limit.set(Some(4096));
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(speed_limit.get(), Some(4096));
+        tokio::time::sleep(Duration::from_secs(10 * 3600)).await;
+        assert_eq!(speed_limit.get(), Some(1024 * 1024));
+        assert_eq!(speed_limit.rule(), None);
+    }
+}
@@ -0,0 +1,30 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use anyhow::Result;
+use http_downloader::DownloadingEndCause;
+use tokio::sync;
+
+use crate::download::Download;
+
+/// Runs every download, at most `max_concurrent` of them at the same time, in queue order.
+/// Paused downloads do not count towards `max_concurrent`.
+pub async fn run_all(downloads: &[Arc<Download>], max_concurrent: NonZeroUsize) -> Result<Vec<Result<DownloadingEndCause>>> {
+    let semaphore = Arc::new(sync::Semaphore::new(max_concurrent.get()));
+    let handles = downloads
+        .iter()
+        .map(|download| {
+            let download = download.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                download.run(&semaphore).await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        results.push(handle.await?);
+    }
+    Ok(results)
+}
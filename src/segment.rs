@@ -0,0 +1,93 @@
+use std::ops::Range;
+
+/// The state of a part of the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentState {
+    Done,
+    /// No connection has started it yet.
+    Pending,
+    /// Left to download on connection number `n`, counting from 1.
+    Downloading(usize),
+    /// Was downloading when the download failed, until it is retried.
+    Failed,
+}
+
+impl SegmentState {
+    /// How a cell in this state is drawn in the progress bar.
+    pub fn glyph(self) -> char {
+        match self {
+            SegmentState::Done => '█',
+            SegmentState::Pending => ' ',
+            SegmentState::Downloading(_) => '▓',
+            SegmentState::Failed => '×',
+        }
+    }
+}
+
+/// Which parts of a file are done, pending or left to each connection, sampled while it
+/// downloads.
+#[derive(Clone, Debug)]
+pub struct SegmentMap {
+    total_len: u64,
+    /// Anything not covered is done.
+    segments: Vec<(Range<u64>, SegmentState)>,
+}
+
+impl SegmentMap {
+    pub fn new(total_len: u64) -> Self {
+        Self { total_len, segments: Vec::new() }
+    }
+
+    pub fn add(&mut self, range: Range<u64>, state: SegmentState) {
+        let range = range.start.min(self.total_len)..range.end.min(self.total_len);
+        if !range.is_empty() && state != SegmentState::Done {
+            self.segments.push((range, state));
+        }
+    }
+
+    /// Gives back what the connections had left once the download was cancelled, which
+    /// keeps what they received.
+    pub fn stop(&mut self) {
+        for (_, state) in self.segments.iter_mut() {
+            if let SegmentState::Downloading(_) = state {
+                *state = SegmentState::Pending;
+            }
+        }
+    }
+
+    /// Marks the whole `ranges` of the connections as failed, a failed download resumes
+    /// their chunks from the start.
+    pub fn fail(&mut self, ranges: impl IntoIterator<Item = Range<u64>>) {
+        self.segments.retain(|(_, state)| !matches!(state, SegmentState::Downloading(_)));
+        for range in ranges {
+            self.add(range, SegmentState::Failed);
+        }
+    }
+
+    /// Splits the file into `count` cells of the same size, each in the state covering
+    /// most of it.
+    pub fn cells(&self, count: usize) -> Vec<SegmentState> {
+        let offset = |n: usize| (u128::from(self.total_len) * n as u128 / count as u128) as u64;
+        (0..count)
+            .map(|n| {
+                let start = offset(n);
+                // a file smaller than the map shows a byte in several cells
+                let end = offset(n + 1).max(start + 1);
+                let mut lens = vec![(SegmentState::Done, end - start)];
+                for (range, state) in self.segments.iter() {
+                    let len = range.end.min(end).saturating_sub(range.start.max(start));
+                    if len == 0 {
+                        continue;
+                    }
+                    lens[0].1 = lens[0].1.saturating_sub(len);
+                    match lens.iter_mut().find(|(n, _)| n == state) {
+                        Some((_, n)) => *n += len,
+                        None => lens.push((*state, len)),
+                    }
+                }
+                // the first of the largest wins, so a tie keeps the cell done
+                lens.iter().rev().max_by_key(|(_, len)| *len).map(|(state, _)| *state).unwrap()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+// 128 + signal number, like a shell reports a process killed by the signal
+pub const EXIT_SIGHUP: i32 = 129;
+pub const EXIT_SIGINT: i32 = 130;
+pub const EXIT_SIGTERM: i32 = 143;
+// what timeout(1) exits with
+pub const EXIT_TIMEOUT: i32 = 124;
+
+/// Resolves with the exit code to use once the process is asked to stop, either by a
+/// signal or through `interrupt_receiver` (in raw mode Ctrl-C is a key press, not SIGINT).
+pub async fn interrupted(interrupt_receiver: &mut mpsc::UnboundedReceiver<i32>) -> Result<i32> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        Ok(tokio::select! {
+            _ = interrupt.recv() => EXIT_SIGINT,
+            _ = terminate.recv() => EXIT_SIGTERM,
+            _ = hangup.recv() => EXIT_SIGHUP,
+            Some(exit_code) = interrupt_receiver.recv() => exit_code,
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(tokio::select! {
+            r = tokio::signal::ctrl_c() => {
+                r?;
+                EXIT_SIGINT
+            }
+            Some(exit_code) = interrupt_receiver.recv() => exit_code,
+        })
+    }
+}
@@ -0,0 +1,113 @@
+use std::num::NonZeroUsize;
+
+/// Byte units in the order of [`crate::progress::ProgressBar::byte_unit`], powers of 1024.
+pub const BYTE_UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+/// Parses a size like `4096`, `512K`, `4MiB` or `1.5G`, or a rate in bits like `10Mbit`.
+///
+/// `K`, `M`, `G` and `T` (with or without `B` or `iB`) are powers of 1024 as in the
+/// progress display, `Kbit`, `Mbit` and `Gbit` are powers of 1000 like network speeds.
+pub fn parse(value: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid size `{value}`, expected a number with an optional unit like 512K, 4MiB, 1.5G or 10Mbit");
+    let trimmed = value.trim();
+    let index = trimmed.find(|n: char| !n.is_ascii_digit() && n != '.').unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(index);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let unit = unit.trim();
+    let multiplier = match unit.strip_suffix("bit").or_else(|| unit.strip_suffix("bits")) {
+        Some(prefix) => {
+            let exponent = match prefix.to_ascii_lowercase().as_str() {
+                "" => 0,
+                "k" => 1,
+                "m" => 2,
+                "g" => 3,
+                _ => return Err(invalid()),
+            };
+            1000f64.powi(exponent) / 8.0
+        }
+        None => {
+            let prefix = unit.strip_suffix("iB").or_else(|| unit.strip_suffix('B')).unwrap_or(unit);
+            let exponent = match prefix.to_ascii_lowercase().as_str() {
+                "" => 0,
+                "k" => 1,
+                "m" => 2,
+                "g" => 3,
+                "t" => 4,
+                _ => return Err(invalid()),
+            };
+            1024f64.powi(exponent)
+        }
+    };
+    let size = (number * multiplier).round();
+    if size >= usize::MAX as f64 {
+        return Err(format!("size `{value}` is too large"));
+    }
+    Ok(size as usize)
+}
+
+pub fn parse_non_zero(value: &str) -> Result<NonZeroUsize, String> {
+    NonZeroUsize::new(parse(value)?).ok_or_else(|| format!("size `{value}` must be larger than 0"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse("4096"), Ok(4096));
+        assert_eq!(parse(" 4096 "), Ok(4096));
+        assert_eq!(parse("512B"), Ok(512));
+    }
+
+    #[test]
+    fn powers_of_1024() {
+        assert_eq!(parse("512K"), Ok(512 * 1024));
+        assert_eq!(parse("512k"), Ok(512 * 1024));
+        assert_eq!(parse("4MiB"), Ok(4 * 1024 * 1024));
+        assert_eq!(parse("4MB"), Ok(4 * 1024 * 1024));
+        assert_eq!(parse("1.5G"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse("2 T"), Ok(2 << 40));
+    }
+
+    #[test]
+    fn bits() {
+        assert_eq!(parse("10Mbit"), Ok(1_250_000));
+        assert_eq!(parse("10Mbits"), Ok(1_250_000));
+        assert_eq!(parse("1Gbit"), Ok(125_000_000));
+        assert_eq!(parse("100kbit"), Ok(12_500));
+        assert_eq!(parse("12bit"), Ok(2));
+    }
+
+    #[test]
+    fn rounds() {
+        assert_eq!(parse("0.1"), Ok(0));
+        assert_eq!(parse("0.5K"), Ok(512));
+        assert_eq!(parse("1.0001K"), Ok(1024));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse("10Mb").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("M").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("-1").is_err());
+        assert!(parse("4X").is_err());
+        assert!(parse("4Pbit").is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        assert_eq!(parse("99999999999999999999T"), Err("size `99999999999999999999T` is too large".to_string()));
+        assert_eq!(parse("16777216T").map_err(|_| ()), Err(()));
+        assert_eq!(parse("16777215T"), Ok(16_777_215 << 40));
+    }
+
+    #[test]
+    fn non_zero() {
+        assert_eq!(parse_non_zero("1K").map(NonZeroUsize::get), Ok(1024));
+        assert!(parse_non_zero("0").is_err());
+        assert!(parse_non_zero("0.1").is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::io::{stdout, Stdout, Write};
+use std::num::NonZeroU8;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossterm::{execute, queue};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+
+use tokio::sync;
+
+use crate::download::{Download, DownloadStatus};
+use crate::graph::{self, LIMIT_LINE};
+use crate::layout::fit;
+use crate::limit::{format_byte_count_per, format_speed_limit, GlobalSpeedLimiter, SpeedLimit};
+use crate::progress::{format_duration, Progress, ProgressBar, write_result};
+use crate::segment::SegmentState;
+use crate::signal;
+use crate::size;
+
+const TICK: Duration = Duration::from_millis(100);
+const DETAIL_HEIGHT: usize = 8;
+// the speed history column of the table is left out on narrower terminals
+const MIN_HISTORY_COLS: usize = 100;
+const HISTORY_WIDTH: usize = 12;
+// the scale of the speed chart, as wide as `1023.99 KiB/s`
+const CHART_LABEL_WIDTH: usize = 13;
+// the colors of the connections in the segment map, done is green and failed red
+const CONNECTION_COLORS: [Color; 8] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::DarkCyan,
+    Color::DarkYellow,
+    Color::DarkMagenta,
+    Color::DarkBlue,
+];
+
+struct Line {
+    text: String,
+    attribute: Attribute,
+    /// The color of each character, the rest is drawn in the default color.
+    colors: Vec<Option<Color>>,
+}
+
+impl Line {
+    fn new(text: String) -> Self {
+        Self::with_attribute(text, Attribute::Reset)
+    }
+
+    fn with_attribute(text: String, attribute: Attribute) -> Self {
+        Self { text, attribute, colors: Vec::new() }
+    }
+
+    fn with_colors(text: String, colors: Vec<Option<Color>>) -> Self {
+        Self { text, attribute: Attribute::Reset, colors }
+    }
+
+    /// The cells of a segment map between brackets.
+    fn segments(cells: &[SegmentState]) -> Self {
+        let text = format!(" [{}]", cells.iter().map(|n| n.glyph()).collect::<String>());
+        let colors = [None, None].into_iter()
+            .chain(cells.iter().map(|n| segment_color(*n)))
+            .collect();
+        Self::with_colors(text, colors)
+    }
+}
+
+fn segment_color(state: SegmentState) -> Option<Color> {
+    match state {
+        SegmentState::Done => Some(Color::Green),
+        SegmentState::Pending => None,
+        SegmentState::Downloading(n) => Some(CONNECTION_COLORS[(n - 1) % CONNECTION_COLORS.len()]),
+        SegmentState::Failed => Some(Color::Red),
+    }
+}
+
+/// Full screen interface: a table of all downloads, a detail pane for the
+/// selected one and a status bar.
+pub struct Tui {
+    downloads: Vec<Arc<Download>>,
+    selected: usize,
+    bars: HashMap<usize, ProgressBar>,
+    speed_limit: SpeedLimit,
+    global_speed_limiter: Arc<GlobalSpeedLimiter>,
+    /// The speed limit being typed after pressing `l`.
+    input: Option<String>,
+    /// Shown in the status bar until the next key press.
+    message: Option<String>,
+    interrupt_sender: sync::mpsc::UnboundedSender<i32>,
+    stdout: Stdout,
+    /// Drives the bars of downloads of unknown size.
+    started_at: Instant,
+    /// The detail pane shows the speed chart instead, toggled with `g`.
+    show_graph: bool,
+}
+
+impl Tui {
+    pub fn new(
+        downloads: Vec<Arc<Download>>,
+        speed_limit: SpeedLimit,
+        global_speed_limiter: Arc<GlobalSpeedLimiter>,
+        interrupt_sender: sync::mpsc::UnboundedSender<i32>,
+    ) -> Self {
+        Self {
+            downloads,
+            selected: 0,
+            bars: HashMap::new(),
+            speed_limit,
+            global_speed_limiter,
+            input: None,
+            message: None,
+            interrupt_sender,
+            stdout: stdout(),
+            started_at: Instant::now(),
+            show_graph: false,
+        }
+    }
+
+    /// Blocks until every download ended, so it has to run on a blocking thread.
+    pub fn run(mut self) -> Result<()> {
+        let guard = TerminalGuard::enter()?;
+        loop {
+            let is_all_end = self.downloads.iter().all(|n| n.status().is_end());
+            self.draw()?;
+            if is_all_end {
+                break;
+            }
+            if event::poll(TICK)? {
+                match event::read()? {
+                    Event::Key(key) if key.kind != KeyEventKind::Release => self.handle_key(key),
+                    // the terminal may have rewrapped the last frame
+                    Event::Resize(_, _) => queue!(self.stdout, Clear(ClearType::All))?,
+                    _ => {}
+                }
+            }
+        }
+        drop(guard);
+
+        let mut buf = String::new();
+        for download in self.downloads.iter() {
+            write_result(&mut buf, download)?;
+        }
+        print!("{buf}");
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        self.message = None;
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.input = None,
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Enter => {
+                    match input.trim() {
+                        "" | "off" => self.speed_limit.set(None),
+                        value => match size::parse(value) {
+                            Ok(byte_count_per) => self.speed_limit.set(Some(byte_count_per)),
+                            Err(err) => self.message = Some(err),
+                        },
+                    }
+                    self.input = None;
+                }
+                KeyCode::Esc => self.input = None,
+                _ => {}
+            }
+            return;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.selected = (self.selected + 1).min(self.downloads.len() - 1),
+            KeyCode::Home => self.selected = 0,
+            KeyCode::End => self.selected = self.downloads.len() - 1,
+            KeyCode::Char(' ') => self.downloads[self.selected].toggle_pause(),
+            KeyCode::Char('c') if key.modifiers.is_empty() => self.downloads[self.selected].stop(false),
+            KeyCode::Char('d') => self.downloads[self.selected].stop(true),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.speed_limit.increase(),
+            // the limit applies to each download, so it starts at half of the fastest one
+            KeyCode::Char('-') => self.speed_limit.decrease(self.downloads.iter().map(|n| n.speed()).max().unwrap_or(0)),
+            KeyCode::Char('l') => self.input = Some(String::new()),
+            KeyCode::Char('g') => self.show_graph = !self.show_graph,
+            KeyCode::Char('[') | KeyCode::Char(']') => {
+                let download = &self.downloads[self.selected];
+                let connection_count = download.connection_count();
+                let connection_count = if key.code == KeyCode::Char('[') {
+                    NonZeroU8::new(connection_count.get() - 1)
+                } else {
+                    connection_count.checked_add(1)
+                };
+                if let Some(connection_count) = connection_count {
+                    download.set_connection_count(connection_count);
+                }
+            }
+            KeyCode::Char('q') => {
+                for download in self.downloads.iter() {
+                    download.stop(false);
+                }
+            }
+            // raw mode swallows SIGINT
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let _ = self.interrupt_sender.send(signal::EXIT_SIGINT);
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        let (cols, rows) = terminal::size().unwrap_or((80, 24));
+        let (cols, rows) = (usize::from(cols), usize::from(rows));
+        // room for the connection table, which is empty for a moment whenever chunks end
+        let connection_table_height = match self.downloads.get(self.selected) {
+            Some(download) if download.status() == DownloadStatus::Downloading && download.total_len().is_some() => {
+                usize::from(download.connection_count().get()) + 1
+            }
+            _ => 0,
+        };
+        let min_detail_height = (DETAIL_HEIGHT + connection_table_height).min(rows.saturating_sub(5));
+        let mut detail_lines = Vec::new();
+        if self.show_graph {
+            self.graph_lines(cols, min_detail_height, &mut detail_lines);
+        } else {
+            self.detail_lines(cols, &mut detail_lines)?;
+        }
+        let detail_height = detail_lines.len().max(min_detail_height).min(rows.saturating_sub(5));
+        let table_height = rows.saturating_sub(detail_height + 4);
+
+        let mut lines = Vec::with_capacity(rows);
+        lines.push(Line::with_attribute(format!(" hd - {} downloads", self.downloads.len()), Attribute::Reverse));
+        lines.push(Line::with_attribute(
+            Self::table_row(cols, " ", "#", "Name", "Size", "Progress", "Speed", "History", "ETA", "State"),
+            Attribute::Bold,
+        ));
+        let first_row = (self.selected + 1).saturating_sub(table_height);
+        for (index, download) in self.downloads.iter().enumerate().skip(first_row).take(table_height) {
+            let line = self.download_row(cols, index, download);
+            lines.push(if index == self.selected {
+                Line::with_attribute(line, Attribute::Reverse)
+            } else {
+                Line::new(line)
+            });
+        }
+        while lines.len() < table_height + 2 {
+            lines.push(Line::new(String::new()));
+        }
+        lines.push(Line::new("─".repeat(cols)));
+        lines.extend(detail_lines);
+        lines.truncate(rows.saturating_sub(1));
+        while lines.len() < rows.saturating_sub(1) {
+            lines.push(Line::new(String::new()));
+        }
+        lines.push(Line::with_attribute(self.status_line(), Attribute::Reverse));
+
+        for (row, line) in lines.iter().enumerate() {
+            queue!(self.stdout, MoveTo(0, row as u16), SetAttribute(line.attribute))?;
+            let text = fit(&line.text, cols);
+            if line.colors.is_empty() {
+                queue!(self.stdout, Print(text))?;
+            } else {
+                let colors = line.colors.iter().copied().chain(std::iter::repeat(None));
+                for (c, color) in text.chars().zip(colors) {
+                    match color {
+                        Some(color) => queue!(self.stdout, SetForegroundColor(color), Print(c), ResetColor)?,
+                        None => queue!(self.stdout, Print(c))?,
+                    }
+                }
+            }
+            queue!(self.stdout, SetAttribute(Attribute::Reset))?;
+        }
+        self.stdout.flush()?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn table_row(cols: usize, marker: &str, index: &str, name: &str, size: &str, progress: &str, speed: &str, history: &str, eta: &str, state: &str) -> String {
+        let history = if cols >= MIN_HISTORY_COLS { format!(" {}", fit(history, HISTORY_WIDTH)) } else { String::new() };
+        let name_width = cols.saturating_sub(72 + history.chars().count()).max(8);
+        format!(
+            "{marker}{index:>3} {} {size:>10} {progress:<17} {speed:>12}{history} {eta:>9} {state}",
+            fit(name, name_width)
+        )
+    }
+
+    fn download_row(&self, cols: usize, index: usize, download: &Download) -> String {
+        let status = download.status();
+        let downloaded_len = if status == DownloadStatus::Verifying { download.verified_len() } else { download.downloaded_len() };
+        let total_len = download.total_len();
+        let speed = download.smoothed_speed();
+        let progress = match total_len {
+            Some(total_len) => {
+                let percent = (downloaded_len * 100 / total_len).min(100);
+                let filled = (percent / 10) as usize;
+                format!("[{}{}] {percent:>3}%", "█".repeat(filled), " ".repeat(10 - filled))
+            }
+            None if status == DownloadStatus::Downloading => format!("[{}]", ProgressBar::bounce(10, self.started_at.elapsed())),
+            None => "-".to_string(),
+        };
+        let eta = download.eta().map(format_duration).unwrap_or_else(|| "-".to_string());
+        Self::table_row(
+            cols,
+            if index == self.selected { ">" } else { " " },
+            &(index + 1).to_string(),
+            &download.file_name(),
+            &total_len.map(format_bytes).unwrap_or_else(|| "-".to_string()),
+            &progress,
+            &if speed > 0 { format!("{}/s", format_bytes(speed)) } else { "-".to_string() },
+            &graph::sparkline(&download.speed_history(), HISTORY_WIDTH),
+            &eta,
+            &status.to_string(),
+        )
+    }
+
+    fn detail_lines(&mut self, cols: usize, lines: &mut Vec<Line>) -> Result<()> {
+        let Some(download) = self.downloads.get(self.selected) else {
+            return Ok(());
+        };
+        let status = download.status();
+        lines.push(Line::with_attribute(format!(" {}", download.file_name()), Attribute::Bold));
+        lines.push(Line::new(format!(" URL:      {}", download.url)));
+        lines.push(Line::new(format!(" Save To:  {}", download.file_path().display())));
+        lines.push(Line::new(format!(
+            " Connections: {}{}",
+            download.connection_count(),
+            if download.is_auto() { " (auto)" } else { "" }
+        )));
+        lines.push(Line::new(match &status {
+            DownloadStatus::Failed(err) => format!(" State:    Failed: {err}"),
+            DownloadStatus::Retrying { error, .. } => format!(" State:    {status}: {error}"),
+            status => format!(" State:    {status}"),
+        }));
+        match (status, download.total_len()) {
+            (DownloadStatus::Downloading, _) => {
+                let bar = self.bars
+                    .entry(download.id)
+                    .or_insert_with(|| ProgressBar::new(usize::MAX));
+                let buf = bar.update(cols.saturating_sub(2), &Progress::of(download))?;
+                // the bar is the second line
+                let mut bar_lines = buf.lines().map(|n| Line::new(format!(" {n}"))).collect::<Vec<_>>();
+                if !bar.cells().is_empty() {
+                    if let Some(line) = bar_lines.get_mut(1) {
+                        *line = Line::segments(bar.cells());
+                    }
+                }
+                lines.extend(bar_lines);
+                self.connection_lines(download, lines);
+            }
+            (DownloadStatus::Verifying, Some(total_len)) => {
+                lines.push(Line::new(format!(
+                    " Verified: {} / {}",
+                    format_bytes(download.verified_len()),
+                    format_bytes(total_len)
+                )));
+            }
+            (_, total_len) => {
+                lines.push(Line::new(format!(
+                    " Size:     {} / {}",
+                    format_bytes(download.downloaded_len()),
+                    total_len.map(format_bytes).unwrap_or_else(|| "-".to_string())
+                )));
+                // what a paused or failed download got done
+                if let Some(segment_map) = download.segment_map() {
+                    lines.push(Line::segments(&segment_map.cells(cols.saturating_sub(4))));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The speed history of the selected download as a chart of `height` lines with the
+    /// speed limit drawn across it.
+    fn graph_lines(&self, cols: usize, height: usize, lines: &mut Vec<Line>) {
+        let Some(download) = self.downloads.get(self.selected) else {
+            return;
+        };
+        lines.push(Line::with_attribute(format!(" {}", download.file_name()), Attribute::Bold));
+        let chart_width = cols.saturating_sub(CHART_LABEL_WIDTH + 4);
+        let speed_history = download.speed_history();
+        let samples = &speed_history[speed_history.len().saturating_sub(chart_width)..];
+        let limit = download.speed_limit().map(|n| n as u64);
+        let Some(max) = samples.iter().copied().max() else {
+            lines.push(Line::new(" Speed: no samples yet".to_string()));
+            return;
+        };
+        let min = samples.iter().copied().min().unwrap_or(0);
+        let average = samples.iter().sum::<u64>() / samples.len() as u64;
+        lines.push(Line::new(format!(
+            " Speed over {}: min {}/s  avg {}/s  max {}/s  limit {}",
+            format_duration(Duration::from_secs(samples.len() as u64)),
+            format_bytes(min),
+            format_bytes(average),
+            format_bytes(max),
+            format_byte_count_per(limit.map(|n| n as usize)),
+        )));
+
+        // a limit far above the speed would flatten the chart
+        let scale = match limit {
+            Some(limit) if limit <= max.saturating_mul(2) => max.max(limit),
+            _ => max,
+        };
+        let chart = graph::chart(samples, chart_width, height.saturating_sub(lines.len()), scale, limit);
+        let last_row = chart.len().saturating_sub(1);
+        for (row, chart_line) in chart.into_iter().enumerate() {
+            let label = match row {
+                0 => format!("{}/s ┤", format_bytes(scale)),
+                _ if row == last_row => "0 ┤".to_string(),
+                _ => "│".to_string(),
+            };
+            let text = format!(" {label:>width$} {chart_line}", width = CHART_LABEL_WIDTH + 2);
+            let colors = text.chars().map(|n| (n == LIMIT_LINE).then_some(Color::Red)).collect();
+            lines.push(Line::with_colors(text, colors));
+        }
+    }
+
+    /// A table of the running connections, numbered like in the segment map.
+    fn connection_lines(&self, download: &Download, lines: &mut Vec<Line>) {
+        let connections = download.connections();
+        if connections.is_empty() {
+            return;
+        }
+        lines.push(Line::with_attribute(
+            format!(" {:>4}  {:<27} {:>5} {:>12} {:>7}", "Conn", "Range", "Done", "Speed", "Retries"),
+            Attribute::Bold,
+        ));
+        for (index, connection) in connections.iter().enumerate() {
+            let (start, end) = connection.range;
+            let range = format!("{} - {}", format_bytes(start), format_bytes(end + 1));
+            let done = connection.downloaded_len * 100 / (end - start + 1);
+            let speed = connection.speed.map(|n| format!("{}/s", format_bytes(n))).unwrap_or_else(|| "-".to_string());
+            let text = format!(" {:>4}  {range:<27} {done:>3} % {speed:>12} {:>7}", index + 1, connection.retries);
+            let color = segment_color(SegmentState::Downloading(index + 1));
+            lines.push(Line::with_colors(text, vec![color; 5]));
+        }
+    }
+
+    /// Combined speed of all running downloads.
+    fn speed(&self) -> u64 {
+        self.downloads.iter().map(|n| n.speed()).sum()
+    }
+
+    fn status_line(&self) -> String {
+        if let Some(input) = &self.input {
+            return format!(" Speed limit per download (e.g. 512K, 2M or 10Mbit, empty for none): {input}█");
+        }
+        if let Some(message) = &self.message {
+            return format!(" {message}");
+        }
+        let (mut active, mut queued, mut paused, mut finished, mut failed) = (0, 0, 0, 0, 0);
+        for download in self.downloads.iter() {
+            match download.status() {
+                DownloadStatus::Downloading | DownloadStatus::Verifying => active += 1,
+                DownloadStatus::Queued | DownloadStatus::Retrying { .. } => queued += 1,
+                DownloadStatus::Paused => paused += 1,
+                DownloadStatus::Finished => finished += 1,
+                DownloadStatus::Failed(_) => failed += 1,
+                DownloadStatus::Cancelled => {}
+            }
+        }
+        let mut limit = format_speed_limit(&self.speed_limit);
+        if let Some(byte_count_per) = self.global_speed_limiter.get() {
+            limit.push_str(&format!(", total {}", format_byte_count_per(Some(byte_count_per))));
+        }
+        format!(
+            " {active} active, {queued} queued, {paused} paused, {finished} finished, {failed} failed │ {}/s │ limit {limit} │ space pause  c cancel  d delete  [/] connections  -/+/l limit  g graph  q quit",
+            format_bytes(self.speed())
+        )
+    }
+}
+
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+pub fn restore_terminal() {
+    let _ = execute!(stdout(), Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+}
+
+fn format_bytes(bytes_count: u64) -> String {
+    let (size, unit) = ProgressBar::byte_unit(bytes_count);
+    format!("{size:.2} {unit}")
+}